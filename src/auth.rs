@@ -0,0 +1,253 @@
+//! Authenticating remote workers and the coordinator to each other,
+//! with HMAC-SHA256.
+//!
+//! The coordinator has a master secret, and each worker has its own
+//! key derived from that and its name (see `worker_key`), so a worker
+//! can only ever claim to be itself.
+
+use std::io::File;
+use std::rand::{OsRng, Rng};
+
+use serialize::hex::{FromHex, ToHex};
+
+static K: [u32, ..64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+static H0: [u32, ..8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// The SHA-256 digest of `data`.
+pub fn sha256(data: &[u8]) -> [u8, ..32] {
+    let mut msg = data.to_vec();
+    let bit_len = data.len() as u64 * 8;
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    for i in range(0u, 8).rev() {
+        msg.push((bit_len >> (i * 8)) as u8);
+    }
+
+    let mut h = H0;
+    for chunk in msg.as_slice().chunks(64) {
+        let mut w = [0u32, ..64];
+        for i in range(0u, 16) {
+            w[i] = (chunk[4 * i] as u32 << 24) | (chunk[4 * i + 1] as u32 << 16) |
+                   (chunk[4 * i + 2] as u32 << 8) | chunk[4 * i + 3] as u32;
+        }
+        for i in range(16u, 64) {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16] + s0 + w[i - 7] + s1;
+        }
+
+        let mut v = h;
+        for i in range(0u, 64) {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7] + s1 + ch + K[i] + w[i];
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0 + maj;
+
+            v = [t1 + t2, v[0], v[1], v[2], v[3] + t1, v[4], v[5], v[6]];
+        }
+        for i in range(0u, 8) {
+            h[i] = h[i] + v[i];
+        }
+    }
+
+    let mut out = [0u8, ..32];
+    for i in range(0u, 8) {
+        for j in range(0u, 4) {
+            out[4 * i + j] = (h[i] >> (24 - 8 * j)) as u8;
+        }
+    }
+    out
+}
+
+/// The HMAC-SHA256 of `data` under `key`.
+pub fn hmac(key: &[u8], data: &[u8]) -> [u8, ..32] {
+    let mut block = [0u8, ..64];
+    if key.len() > 64 {
+        let hashed = sha256(key);
+        for (b, k) in block.iter_mut().zip(hashed.iter()) { *b = *k }
+    } else {
+        for (b, k) in block.iter_mut().zip(key.iter()) { *b = *k }
+    }
+
+    let mut inner: Vec<u8> = block.iter().map(|b| *b ^ 0x36).collect();
+    inner.push_all(data);
+    let mut outer: Vec<u8> = block.iter().map(|b| *b ^ 0x5c).collect();
+    outer.push_all(&sha256(inner.as_slice()));
+    sha256(outer.as_slice())
+}
+
+/// Compare two MACs, in time independent of where they differ: every
+/// byte is looked at, and only the length (which isn't secret) can
+/// stop it early.
+pub fn verify(expected: &[u8], actual: &[u8]) -> bool {
+    if expected.len() != actual.len() {
+        return false
+    }
+    let mut diff = 0u8;
+    for (a, b) in expected.iter().zip(actual.iter()) {
+        diff |= *a ^ *b;
+    }
+    diff == 0
+}
+
+/// Compare a MAC to its hex encoding, as received over the wire.
+pub fn verify_hex(expected: &[u8], actual: &str) -> bool {
+    match actual.from_hex() {
+        Ok(actual) => verify(expected, actual.as_slice()),
+        Err(_) => false
+    }
+}
+
+/// The key of the worker called `name`, under the coordinator's
+/// `master` secret.
+pub fn worker_key(master: &[u8], name: &str) -> [u8, ..32] {
+    hmac(master, format!("worker:{}", name).as_bytes())
+}
+
+/// A fresh random nonce, in hex.
+pub fn nonce() -> String {
+    let mut rng = OsRng::new().ok().expect("couldn't open the OS RNG");
+    let mut nonce = [0u8, ..16];
+    rng.fill_bytes(&mut nonce);
+    nonce.to_hex()
+}
+
+/// The contents of the secret file at `p`, with surrounding whitespace
+/// removed.
+pub fn load_secret(p: &Path) -> Vec<u8> {
+    let contents = File::open(p).read_to_string()
+        .ok().expect(format!("couldn't read secret from {}", p.display()).as_slice());
+    contents.as_slice().trim().as_bytes().to_vec()
+}
+
+/// A worker's key, as written by `multibuilder worker-key`, from the
+/// file at `p`.
+pub fn load_worker_key(p: &Path) -> Vec<u8> {
+    let hex = load_secret(p);
+    String::from_utf8(hex).ok()
+        .and_then(|s| s.as_slice().from_hex().ok())
+        .expect(format!("{} doesn't contain a hex key", p.display()).as_slice())
+}
+
+#[cfg(test)]
+mod test {
+    use serialize::hex::{FromHex, ToHex};
+
+    use super::{sha256, hmac, verify, verify_hex};
+
+    // from FIPS 180-2 and the NIST CAVP examples.
+    #[test]
+    fn sha256_vectors() {
+        let cases = [
+            ("", "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"),
+            ("abc", "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            ("abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+             "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"),
+            ("abcdefghbcdefghicdefghijdefghijkefghijklfghijklmghijklmnhijklmno\
+              ijklmnopjklmnopqklmnopqrlmnopqrsmnopqrstnopqrstu",
+             "cf5b16a778af8380036ce59e7b0492370b249b11e8f07a51afac45037afee9d1"),
+        ];
+        for &(input, digest) in cases.iter() {
+            assert_eq!(sha256(input.as_bytes()).to_hex().as_slice(), digest);
+        }
+    }
+
+    #[test]
+    fn sha256_million_a() {
+        let input = Vec::from_elem(1000000, b'a');
+        assert_eq!(sha256(input.as_slice()).to_hex().as_slice(),
+                   "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0");
+    }
+
+    // either side of where the padding spills into another block.
+    #[test]
+    fn sha256_padding_boundaries() {
+        let cases = [
+            (55u, "d5e285683cd4efc02d021a5c62014694958901005d6f71e89e0989fac77e4072"),
+            (56, "04c26261370ee7541549d16dee320c723e3fd14671e66a099afe0a377c16888e"),
+            (64, "7ce100971f64e7001e8fe5a51973ecdfe1ced42befe7ee8d5fd6219506b5393c"),
+        ];
+        for &(len, digest) in cases.iter() {
+            let input = Vec::from_elem(len, b'x');
+            assert_eq!(sha256(input.as_slice()).to_hex().as_slice(), digest);
+        }
+    }
+
+    // RFC 4231, test cases 1-4, 6 and 7 (5 is truncated).
+    #[test]
+    fn hmac_vectors() {
+        let cases = [
+            ("0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b", "4869205468657265",
+             "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"),
+            ("4a656665", "7768617420646f2079612077616e7420666f72206e6f7468696e673f",
+             "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+            ("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+             "dddddddddddddddddddddddddddddddddddddddddddddddddd\
+              dddddddddddddddddddddddddddddddddddddddddddddddddd",
+             "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe"),
+            ("0102030405060708090a0b0c0d0e0f10111213141516171819",
+             "cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd\
+              cdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd",
+             "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b"),
+        ];
+        for &(key, data, mac) in cases.iter() {
+            let key = key.from_hex().unwrap();
+            let data = data.from_hex().unwrap();
+            assert_eq!(hmac(key.as_slice(), data.as_slice()).to_hex().as_slice(), mac);
+        }
+
+        // keys longer than a block are hashed first.
+        let key = Vec::from_elem(131, 0xaau8);
+        let cases = [
+            ("Test Using Larger Than Block-Size Key - Hash Key First",
+             "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"),
+            ("This is a test using a larger than block-size key and a larger than \
+              block-size data. The key needs to be hashed before being used by the \
+              HMAC algorithm.",
+             "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2"),
+        ];
+        for &(data, mac) in cases.iter() {
+            assert_eq!(hmac(key.as_slice(), data.as_bytes()).to_hex().as_slice(), mac);
+        }
+    }
+
+    #[test]
+    fn verify_compares_every_byte() {
+        let mac = hmac(b"key", b"message");
+        assert!(verify(&mac, &mac));
+
+        let mut first = mac;
+        first[0] ^= 1;
+        assert!(!verify(&mac, &first));
+        let mut last = mac;
+        last[31] ^= 0x80;
+        assert!(!verify(&mac, &last));
+
+        assert!(!verify(&mac, mac.slice_to(31)));
+        assert!(!verify(&mac, []));
+    }
+
+    #[test]
+    fn verify_hex_rejects_bad_hex() {
+        let mac = hmac(b"key", b"message");
+        assert!(verify_hex(&mac, mac.to_hex().as_slice()));
+        assert!(!verify_hex(&mac, "not hex"));
+        assert!(!verify_hex(&mac, ""));
+    }
+}
@@ -1,26 +1,619 @@
 use git::Sha;
+use std::ascii::StrAsciiExt;
+use std::cmp;
+use std::collections::{HashMap, TreeMap};
 use std::fmt;
+use std::os;
+
+use build_log::LogSettings;
+use cgroup::{CgroupSettings, Usage};
+use rusage::Resources;
+
+/// One thing to build: a commit, in one of the variants of the build
+/// matrix if there is one.
+#[deriving(Clone, PartialEq, Eq, Hash, Encodable, Decodable, Show)]
+pub struct Job {
+    pub hash: Sha,
+    /// `None` without a build matrix.
+    pub variant: Option<String>,
+}
+
+impl Job {
+    pub fn new(hash: Sha, variant: Option<String>) -> Job {
+        Job { hash: hash, variant: variant }
+    }
+
+    /// `<hash>/<variant>`, or just `<hash>` without a variant: how it's
+    /// named in the history, and the path of its output directory.
+    pub fn key(&self) -> String {
+        self.joined("/")
+    }
+
+    /// `<hash>-<variant>`, or just `<hash>`: for files and directories
+    /// that can't be in a directory per commit, like logs and build
+    /// directories.
+    pub fn file_name(&self) -> String {
+        self.joined("-")
+    }
+
+    fn joined(&self, sep: &str) -> String {
+        match self.variant {
+            Some(ref v) => format!("{}{}{}", self.hash.value, sep, v),
+            None => self.hash.value.clone()
+        }
+    }
+
+    /// The inverse of `key`.
+    pub fn from_key(key: &str) -> Job {
+        Job::split(key, '/')
+    }
+
+    /// The inverse of `file_name`. (Hashes never contain `-`.)
+    pub fn from_file_name(name: &str) -> Job {
+        Job::split(name, '-')
+    }
+
+    fn split(s: &str, sep: char) -> Job {
+        let mut parts = s.splitn(sep, 1);
+        let hash = Sha { value: parts.next().unwrap().to_string() };
+        Job::new(hash, parts.next().map(|v| v.to_string()))
+    }
+}
+
+/// A job for tests to use, of a made-up commit.
+#[cfg(test)]
+pub fn test_job(variant: Option<&str>) -> Job {
+    Job::new(Sha { value: "0123456789abcdef".to_string() }, variant.map(|v| v.to_string()))
+}
 
 #[deriving(Show)]
 pub enum BuildInstruction {
-    BuildHash(Sha)
+    BuildJob(Job)
 }
 
 /// Where the result of the build is. Designed to be extended for
 /// distributed building.
 pub enum BuiltLocation {
     Local(Path),
+    /// Built by the remote worker with the given name, in the given
+    /// directory on that machine.
+    Remote(String, String),
 }
 
 impl fmt::Show for BuiltLocation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let Local(ref p) = *self;
-        write!(f, "Local({})", p.display())
+        match *self {
+            Local(ref p) => write!(f, "Local({})", p.display()),
+            Remote(ref worker, ref p) => write!(f, "Remote({}, {})", worker, p),
+        }
     }
 }
 
+/// How a build ended.
 #[deriving(Show)]
-pub enum BuildResult {
-    Success(BuiltLocation, Sha),
-    Failure(Sha)
+pub enum Outcome {
+    Success(BuiltLocation),
+    /// A build command failed.
+    Failure,
+    /// A command ran for too long, or went too long without printing
+    /// anything, and was killed.
+    TimedOut,
+    /// The build was stopped via the control socket.
+    Cancelled,
+    /// The build was killed because multibuilder was stopping.
+    Interrupted,
+    /// The commit was cancelled before its build started.
+    Skipped,
+    /// The build couldn't be done at all, e.g. the clone or checkout
+    /// failed; this says nothing about the commit.
+    Infrastructure,
+}
+
+impl Outcome {
+    /// The word the history records this as.
+    pub fn status(&self) -> &'static str {
+        match *self {
+            Success(_) => "success",
+            Failure => "failure",
+            TimedOut => "timed-out",
+            Cancelled => "cancelled",
+            Interrupted => "interrupted",
+            Skipped => "skipped",
+            Infrastructure => "infrastructure",
+        }
+    }
+
+    /// The unsuccessful outcome recorded as `status`, for passing
+    /// over the network.
+    pub fn from_status(status: &str) -> Option<Outcome> {
+        match status {
+            "failure" => Some(Failure),
+            "timed-out" => Some(TimedOut),
+            "cancelled" => Some(Cancelled),
+            "interrupted" => Some(Interrupted),
+            "skipped" => Some(Skipped),
+            "infrastructure" => Some(Infrastructure),
+            _ => None
+        }
+    }
+}
+
+/// How one step of a build (a build command, or setting up for them)
+/// ended.
+#[deriving(Encodable, Decodable, Clone, PartialEq, Show)]
+pub enum StepOutcome {
+    /// It exited with the given code.
+    Exited(int),
+    /// It was killed by the given signal.
+    Signalled(int),
+    /// It was killed for exceeding a timeout.
+    StepTimedOut,
+    /// It didn't run, because an earlier step didn't succeed.
+    NotRun,
+    /// It couldn't be run, for the given reason.
+    Error(String),
+    /// It was started, but then we lost track of it, for the given
+    /// reason; it was killed.
+    Lost(String),
+}
+
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct Step {
+    /// The command line.
+    pub command: String,
+    pub outcome: StepOutcome,
+    /// How long it ran for, in milliseconds.
+    pub duration_ms: i64,
+    /// Where its output was written, on the machine that ran it.
+    pub log: Option<String>,
+    /// The environment it ran in, if that was hermetic.
+    pub env: Option<TreeMap<String, String>>,
+    /// What it used, if it ran to completion.
+    pub resources: Option<Resources>,
+}
+
+impl Step {
+    pub fn success(&self) -> bool {
+        self.outcome == Exited(0)
+    }
+
+    /// What the step was and how it went, with where its log is, for
+    /// status output and the log itself.
+    pub fn describe(&self) -> String {
+        let outcome = match self.outcome {
+            Exited(code) => format!("exited with {}", code),
+            Signalled(sig) => format!("killed by signal {}", sig),
+            StepTimedOut => "timed out".to_string(),
+            NotRun => return format!("`{}`: not run", self.command),
+            Error(ref e) => format!("couldn't run: {}", e),
+            Lost(ref e) => format!("lost track of it: {}", e),
+        };
+        let log = match self.log {
+            Some(ref l) => format!(", log in {}", l),
+            None => String::new(),
+        };
+        format!("`{}`: {} after {}.{:03}s{}", self.command, outcome,
+                self.duration_ms / 1000, self.duration_ms % 1000, log)
+    }
+}
+
+#[deriving(Show)]
+pub struct BuildResult {
+    pub job: Job,
+    pub outcome: Outcome,
+    pub steps: Vec<Step>,
+    /// What the build used, if it had a cgroup to measure it.
+    pub usage: Option<Usage>,
+}
+
+/// How long (in seconds) builds may take before they're killed. `None`
+/// for no limit.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct Timeouts {
+    /// How long each build command may run, unless the command sets
+    /// its own `timeout_seconds`.
+    pub command: Option<i64>,
+    /// How long all the build commands for a commit may run, together.
+    pub build: Option<i64>,
+    /// How long a build command may go without writing anything to
+    /// stdout or stderr.
+    pub no_output: Option<i64>,
+}
+
+impl Timeouts {
+    pub fn none() -> Timeouts {
+        Timeouts { command: None, build: None, no_output: None }
+    }
+}
+
+/// The environment build commands start from.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct Environment {
+    /// Whether to start from an empty environment, rather than
+    /// multibuilder's own (default false).
+    pub hermetic: Option<bool>,
+    /// With `hermetic`, the variables to keep from multibuilder's
+    /// environment.
+    pub allow: Option<Vec<String>>,
+    /// Variables to set for every build command.
+    pub set: Option<HashMap<String, String>>,
+}
+
+impl Environment {
+    /// Everything inherited, nothing set.
+    pub fn inherit() -> Environment {
+        Environment { hermetic: None, allow: None, set: None }
+    }
+
+    pub fn is_hermetic(&self) -> bool {
+        self.hermetic.unwrap_or(false)
+    }
+
+    /// The variables each build command starts with, with `expand`
+    /// applied to the values of `set`.
+    pub fn base(&self, expand: |&str| -> String) -> TreeMap<String, String> {
+        let allow = self.allow.clone().unwrap_or(Vec::new());
+        let mut env = TreeMap::new();
+        for (name, value) in os::env().into_iter() {
+            if !self.is_hermetic() || allow.contains(&name) {
+                env.insert(name, value);
+            }
+        }
+        match self.set {
+            Some(ref vars) => {
+                for (name, value) in vars.iter() {
+                    env.insert(name.clone(), expand(value.as_slice()));
+                }
+            }
+            None => {}
+        }
+        env
+    }
+}
+
+/// Limits on the resources each build command may use, and its
+/// priority. `None` for no limit, or the default priority.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct Limits {
+    /// The size of each process's address space, in bytes.
+    pub address_space: Option<u64>,
+    /// The CPU time each process may use, in seconds.
+    pub cpu_seconds: Option<u64>,
+    /// How many files each process may have open.
+    pub open_files: Option<u64>,
+    /// How many processes the user running the build may have.
+    pub processes: Option<u64>,
+    /// The `nice` level to run at.
+    pub nice: Option<int>,
+    /// The `ionice` scheduling class: 1 (realtime), 2 (best-effort)
+    /// or 3 (idle).
+    pub ionice_class: Option<uint>,
+    /// The `ionice` priority within that class, 0 (highest) to 7.
+    pub ionice_level: Option<uint>,
+}
+
+impl Limits {
+    pub fn none() -> Limits {
+        Limits {
+            address_space: None, cpu_seconds: None, open_files: None, processes: None,
+            nice: None, ionice_class: None, ionice_level: None,
+        }
+    }
+
+    /// The command words to run a command under, to apply these.
+    pub fn prefix(&self) -> Vec<String> {
+        let mut words = Vec::new();
+        match self.nice {
+            Some(n) => words.push_all(["nice".to_string(), "-n".to_string(), n.to_string()]),
+            None => {}
+        }
+        match (self.ionice_class, self.ionice_level) {
+            (None, None) => {}
+            (class, level) => {
+                words.push("ionice".to_string());
+                match class {
+                    Some(c) => words.push_all(["-c".to_string(), c.to_string()]),
+                    None => {}
+                }
+                match level {
+                    Some(l) => words.push_all(["-n".to_string(), l.to_string()]),
+                    None => {}
+                }
+            }
+        }
+
+        let rlimits: Vec<String> = [("as", self.address_space),
+                                    ("cpu", self.cpu_seconds),
+                                    ("nofile", self.open_files),
+                                    ("nproc", self.processes)].iter()
+            .filter_map(|&(name, limit)| limit.map(|l| format!("--{}={}", name, l)))
+            .collect();
+        if !rlimits.is_empty() {
+            words.push("prlimit".to_string());
+            words.push_all(rlimits.as_slice());
+            words.push("--".to_string());
+        }
+        words
+    }
+}
+
+/// Running build commands in their own Linux namespaces (with
+/// `bwrap`), so that they can only change their own checkout.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct Sandbox {
+    /// Whether builds can use the network (default false).
+    pub network: Option<bool>,
+    /// Other paths builds may write to, such as caches. Everything
+    /// else apart from the checkout and `/tmp` is read-only.
+    pub writable: Option<Vec<String>>,
+}
+
+impl Sandbox {
+    /// The command words to run a command in the sandbox, for a build
+    /// checked out at `build_dir`.
+    pub fn prefix(&self, build_dir: &Path) -> Vec<String> {
+        let mut words: Vec<String> = ["bwrap", "--unshare-user", "--die-with-parent",
+                                      "--ro-bind", "/", "/",
+                                      "--dev", "/dev",
+                                      "--proc", "/proc",
+                                      "--tmpfs", "/tmp"].iter()
+            .map(|w| w.to_string())
+            .collect();
+        if !self.network.unwrap_or(false) {
+            words.push("--unshare-net".to_string());
+        }
+
+        let mut writable = vec![build_dir.clone()];
+        writable.extend(self.writable.iter().flat_map(|w| w.iter()).map(|p| Path::new(p.as_slice())));
+        for path in writable.iter() {
+            // bwrap needs absolute paths.
+            let path = format!("{}", os::getcwd().join(path).display());
+            words.push_all(["--bind".to_string(), path.clone(), path]);
+        }
+        words.push("--".to_string());
+        words
+    }
+}
+
+/// How builds are done, other than the commands they run.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct BuildSettings {
+    pub timeouts: Timeouts,
+    pub logs: LogSettings,
+    pub environment: Environment,
+    pub limits: Limits,
+    /// `None` to run builds without isolation.
+    pub sandbox: Option<Sandbox>,
+    /// `None` to not put builds in cgroups.
+    pub cgroups: Option<CgroupSettings>,
+}
+
+/// `s` in single quotes, as one word for `sh`.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace("'", "'\\''"))
+}
+
+/// What a build command can know about the build it's part of: these
+/// are substituted for `{hash}`, `{short_hash}`, `{variant}`,
+/// `{build_dir}`, `{output_dir}` and `{commit_time}` in commands, and
+/// exported as `MULTIBUILDER_HASH` and so on.
+pub struct BuildVars {
+    pub hash: Sha,
+    /// The variant of the build matrix being built, if any.
+    pub variant: Option<String>,
+    /// Where the commit is checked out.
+    pub build_dir: Path,
+    /// Where the artifacts of a successful build will be put, if
+    /// they're put anywhere on this machine.
+    pub output_dir: Option<Path>,
+    /// The commit's timestamp, if it could be found.
+    pub commit_time: Option<i64>,
+}
+
+impl BuildVars {
+    /// The name and value of each variable. Unknown ones are empty.
+    pub fn vars(&self) -> Vec<(&'static str, String)> {
+        let hash = self.hash.value.as_slice();
+        vec![("hash", hash.to_string()),
+             ("short_hash", hash.slice_to(cmp::min(8, hash.len())).to_string()),
+             ("variant", self.variant.clone().unwrap_or(String::new())),
+             ("build_dir", format!("{}", self.build_dir.display())),
+             ("output_dir", self.output_dir.as_ref()
+                                .map(|p| format!("{}", p.display()))
+                                .unwrap_or(String::new())),
+             ("commit_time", self.commit_time.map(|t| t.to_string()).unwrap_or(String::new()))]
+    }
+
+    /// `s` with each `{name}` replaced by the value of that variable.
+    /// What's substituted isn't expanded again.
+    pub fn expand(&self, s: &str) -> String {
+        self.expand_with(s, |value| value.to_string())
+    }
+
+    /// As `expand`, for a line that `sh` will run: the values are
+    /// quoted, so that spaces and the like in them stay as they are.
+    pub fn expand_for_shell(&self, s: &str) -> String {
+        self.expand_with(s, |value| shell_quote(value))
+    }
+
+    fn expand_with(&self, s: &str, quote: |&str| -> String) -> String {
+        let vars = self.vars();
+        let mut expanded = String::new();
+        let mut rest = s;
+        loop {
+            let start = match rest.find('{') {
+                Some(i) => i,
+                None => { expanded.push_str(rest); return expanded }
+            };
+            expanded.push_str(rest.slice_to(start));
+            rest = rest.slice_from(start + 1);
+            let var = vars.iter().find(|&&(name, _)| {
+                rest.starts_with(name) && rest.slice_from(name.len()).starts_with("}")
+            });
+            match var {
+                Some(&(name, ref value)) => {
+                    expanded.push_str(quote(value.as_slice()).as_slice());
+                    rest = rest.slice_from(name.len() + 1);
+                }
+                // not one of ours, so left alone.
+                None => expanded.push_str("{")
+            }
+        }
+    }
+
+    /// The environment variables that are set for each build command.
+    pub fn env(&self) -> Vec<(String, String)> {
+        self.vars().into_iter()
+            .map(|(name, value)| (format!("MULTIBUILDER_{}", name.to_ascii_upper()), value))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::os;
+
+    use git::Sha;
+    use super::{BuildVars, Environment, Job, Limits, Sandbox, Step};
+    use super::{Exited, Lost, StepOutcome};
+
+    fn vars(hash: &str, build_dir: &str) -> BuildVars {
+        BuildVars {
+            hash: Sha { value: hash.to_string() },
+            variant: Some("debug".to_string()),
+            build_dir: Path::new(build_dir),
+            output_dir: None,
+            commit_time: Some(1400000000),
+        }
+    }
+
+    #[test]
+    fn expand_substitutes_each_variable() {
+        let v = vars("0123456789abcdef", "/b/0123");
+        assert_eq!(v.expand("{hash} {short_hash} {variant} {build_dir} [{output_dir}] {commit_time}"),
+                   "0123456789abcdef 01234567 debug /b/0123 [] 1400000000".to_string());
+    }
+
+    #[test]
+    fn expand_is_a_single_pass() {
+        let v = vars("0123456789abcdef", "/b/{hash}");
+        assert_eq!(v.expand("{build_dir}/x"), "/b/{hash}/x".to_string());
+        assert_eq!(v.expand("{nope} {hash"), "{nope} {hash".to_string());
+        assert_eq!(v.expand("{{hash}}"), "{0123456789abcdef}".to_string());
+    }
+
+    #[test]
+    fn short_hash_of_a_short_hash() {
+        assert_eq!(vars("abc", "/b").expand("{short_hash}"), "abc".to_string());
+    }
+
+    #[test]
+    fn expand_for_shell_quotes_values() {
+        let v = vars("0123456789abcdef", "/b/it's a $dir");
+        assert_eq!(v.expand_for_shell("cp x {build_dir}/out && echo {variant}"),
+                   "cp x '/b/it'\\''s a $dir'/out && echo 'debug'".to_string());
+    }
+
+    #[test]
+    fn hermetic_environment_keeps_only_what_is_allowed() {
+        let mut set = HashMap::new();
+        set.insert("CC".to_string(), "clang-{x}".to_string());
+        let env = Environment {
+            hermetic: Some(true),
+            allow: Some(vec!["PATH".to_string()]),
+            set: Some(set),
+        };
+        let base = env.base(|s| s.replace("{x}", "3.5"));
+        assert_eq!(base.find(&"CC".to_string()), Some(&"clang-3.5".to_string()));
+        assert_eq!(base.find(&"PATH".to_string()), os::getenv("PATH").as_ref());
+        assert!(base.keys().all(|k| k.as_slice() == "CC" || k.as_slice() == "PATH"));
+    }
+
+    #[test]
+    fn inherited_environment_is_ours() {
+        let base = Environment::inherit().base(|s| s.to_string());
+        assert_eq!(base.len(), os::env().len());
+        assert_eq!(base.find(&"PATH".to_string()), os::getenv("PATH").as_ref());
+    }
+
+    fn words(ws: &[&str]) -> Vec<String> {
+        ws.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn no_limits_no_prefix() {
+        assert_eq!(Limits::none().prefix(), Vec::new());
+    }
+
+    #[test]
+    fn limits_prefix() {
+        let limits = Limits {
+            address_space: Some(1 << 30), cpu_seconds: None, open_files: Some(256),
+            processes: None, nice: Some(10), ionice_class: Some(3), ionice_level: None,
+        };
+        assert_eq!(limits.prefix(),
+                   words(["nice", "-n", "10", "ionice", "-c", "3",
+                          "prlimit", "--as=1073741824", "--nofile=256", "--"]));
+
+        let limits = Limits { ionice_level: Some(7), ..Limits::none() };
+        assert_eq!(limits.prefix(), words(["ionice", "-n", "7"]));
+    }
+
+    #[test]
+    fn sandbox_prefix() {
+        let base = ["bwrap", "--unshare-user", "--die-with-parent", "--ro-bind", "/", "/",
+                    "--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"];
+
+        let sandbox = Sandbox { network: None, writable: None };
+        let mut expected = words(&base);
+        expected.push_all(words(["--unshare-net", "--bind", "/b/0123", "/b/0123", "--"]).as_slice());
+        assert_eq!(sandbox.prefix(&Path::new("/b/0123")), expected);
+
+        let sandbox = Sandbox { network: Some(true), writable: Some(vec!["/cache".to_string()]) };
+        let mut expected = words(&base);
+        expected.push_all(words(["--bind", "/b/0123", "/b/0123",
+                                 "--bind", "/cache", "/cache", "--"]).as_slice());
+        assert_eq!(sandbox.prefix(&Path::new("/b/0123")), expected);
+    }
+
+    fn step(outcome: StepOutcome) -> Step {
+        Step {
+            command: "make".to_string(),
+            outcome: outcome,
+            duration_ms: 1500,
+            log: Some("0123.log".to_string()),
+            env: None,
+            resources: None,
+        }
+    }
+
+    #[test]
+    fn describe_steps() {
+        assert!(step(Exited(0)).success());
+        assert_eq!(step(Exited(2)).describe(),
+                   "`make`: exited with 2 after 1.500s, log in 0123.log".to_string());
+        let lost = step(Lost("waiting for it failed: ECHILD".to_string()));
+        assert!(!lost.success());
+        assert_eq!(lost.describe(),
+                   "`make`: lost track of it: waiting for it failed: ECHILD after 1.500s, \
+                    log in 0123.log".to_string());
+    }
+
+    #[test]
+    fn job_names() {
+        let hash = Sha { value: "0123abcd".to_string() };
+        let plain = Job::new(hash.clone(), None);
+        let debug = Job::new(hash.clone(), Some("debug-x86.64".to_string()));
+
+        assert_eq!(plain.key(), "0123abcd".to_string());
+        assert_eq!(plain.file_name(), "0123abcd".to_string());
+        assert_eq!(debug.key(), "0123abcd/debug-x86.64".to_string());
+        assert_eq!(debug.file_name(), "0123abcd-debug-x86.64".to_string());
+
+        for job in [plain, debug].iter() {
+            assert_eq!(Job::from_key(job.key().as_slice()), *job);
+            assert_eq!(Job::from_file_name(job.file_name().as_slice()), *job);
+        }
+    }
 }
@@ -0,0 +1,180 @@
+//! The log of each build: everything its commands write to stdout and
+//! stderr, a line at a time and timestamped, in one file per commit
+//! (and variant). Logs of earlier builds of the same commit are rotated
+//! out of the way, rather than overwritten.
+//!
+//! Lines are written as soon as they're output, so a build in progress
+//! can be followed with `tail -f`, or the `tail` control command.
+
+use std::cmp;
+use std::io;
+use std::io::fs;
+use std::io::fs::PathExtensions;
+use std::io::{File, IoResult};
+
+use time;
+
+use build::Job;
+
+/// How big a log may get, unless configured otherwise.
+static DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// How many logs of earlier builds of a commit are kept, unless
+/// configured otherwise.
+static DEFAULT_KEEP: uint = 3;
+
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct LogSettings {
+    /// The directory to write `<hash>.log` (or `<hash>-<variant>.log`)
+    /// in. `None` for
+    /// `<build_parent_dir>/logs`.
+    pub dir: Option<String>,
+    /// How big a log may get; anything after that is dropped (default
+    /// 10 MiB).
+    pub max_bytes: Option<u64>,
+    /// How many logs of earlier builds of a commit to keep, as
+    /// `<hash>.log.1` (the most recent), `<hash>.log.2`, ... (default
+    /// 3).
+    pub keep: Option<uint>,
+    /// Whether to print every line logged to the terminal as well,
+    /// prefixed by the short hash of the commit, and its variant
+    /// (default false).
+    pub echo: Option<bool>,
+}
+
+impl LogSettings {
+    pub fn default() -> LogSettings {
+        LogSettings { dir: None, max_bytes: None, keep: None, echo: None }
+    }
+
+    /// Where the logs go, for builds in `build_dir`.
+    pub fn dir(&self, build_dir: &Path) -> Path {
+        match self.dir {
+            Some(ref d) => Path::new(d.as_slice()),
+            None => build_dir.join("logs"),
+        }
+    }
+
+    /// The log of the most recent build of `job`.
+    pub fn path(&self, build_dir: &Path, job: &Job) -> Path {
+        self.dir(build_dir).join(format!("{}.log", job.file_name()))
+    }
+}
+
+pub struct BuildLog {
+    path: Path,
+    /// `None` if it couldn't be created: a build doesn't fail just
+    /// because its log can't be written.
+    file: Option<File>,
+    written: u64,
+    max_bytes: u64,
+    start_ns: u64,
+    /// What to prefix lines with when printing them to the terminal
+    /// too; `None` if they aren't.
+    echo: Option<String>,
+}
+
+impl BuildLog {
+    /// Start the log of `job` in `build_dir`, moving aside any
+    /// earlier logs of it.
+    pub fn create(build_dir: &Path, job: &Job, settings: &LogSettings) -> BuildLog {
+        let path = settings.path(build_dir, job);
+        let keep = settings.keep.unwrap_or(DEFAULT_KEEP);
+        let file = fs::mkdir_recursive(&path.dir_path(), io::USER_RWX)
+            .and_then(|()| rotate(&path, keep))
+            .and_then(|()| File::create(&path));
+        let file = match file {
+            Ok(f) => Some(f),
+            Err(e) => {
+                warn!("couldn't create the log {}: {}", path.display(), e);
+                None
+            }
+        };
+
+        BuildLog {
+            path: path,
+            file: file,
+            written: 0,
+            max_bytes: settings.max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            start_ns: time::precise_time_ns(),
+            echo: if settings.echo.unwrap_or(false) {
+                let hash = job.hash.value.as_slice();
+                let short = hash.slice_to(cmp::min(8, hash.len()));
+                Some(match job.variant {
+                    Some(ref v) => format!("{}/{}", short, v),
+                    None => short.to_string()
+                })
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Record something about the build, rather than output of it.
+    pub fn note(&mut self, what: &str) {
+        self.write_line("--", what.as_bytes());
+    }
+
+    /// Record a line written to `stream` (`out` or `err`).
+    pub fn output(&mut self, stream: &str, line: &[u8]) {
+        let line = if line.last() == Some(&b'\n') { line.init() } else { line };
+        self.write_line(stream, line);
+    }
+
+    fn write_line(&mut self, tag: &str, line: &[u8]) {
+        match self.echo {
+            Some(ref short) => println!("{} {} | {}", short, tag, String::from_utf8_lossy(line)),
+            None => {}
+        }
+        if self.written >= self.max_bytes {
+            return
+        }
+        let elapsed = (time::precise_time_ns() - self.start_ns) as f64 / 1e9;
+        let prefix = format!("{} {:9.3} {} | ",
+                             time::strftime("%Y-%m-%d %H:%M:%S", &time::now()), elapsed, tag);
+        let len = (prefix.len() + line.len() + 1) as u64;
+
+        let file = match self.file {
+            Some(ref mut f) => f,
+            None => return
+        };
+        let result = if self.written + len > self.max_bytes {
+            self.written = self.max_bytes;
+            file.write_str(format!("{}[truncated: the log is over {} bytes]\n",
+                                   prefix, self.max_bytes).as_slice())
+        } else {
+            self.written += len;
+            write_prefixed(file, prefix.as_slice(), line)
+        };
+        match result {
+            Ok(()) => {}
+            Err(e) => warn!("couldn't write to the log {}: {}", self.path.display(), e)
+        }
+    }
+}
+
+fn write_prefixed(file: &mut File, prefix: &str, line: &[u8]) -> IoResult<()> {
+    try!(file.write_str(prefix));
+    try!(file.write(line));
+    file.write_str("\n")
+}
+
+fn numbered(path: &Path, n: uint) -> Path {
+    path.with_filename(format!("{}.{}", path.filename_str().unwrap(), n))
+}
+
+/// Move `path` to `path.1`, `path.1` to `path.2`, and so on, dropping
+/// whatever was at `path.<keep>`.
+fn rotate(path: &Path, keep: uint) -> IoResult<()> {
+    for n in range(0, keep).rev() {
+        let from = if n == 0 { path.clone() } else { numbered(path, n) };
+        if from.exists() {
+            try!(fs::rename(&from, &numbered(path, n + 1)));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,201 @@
+//! Putting each build in its own (v2) cgroup, so that what it uses
+//! can be measured, its memory limited, and anything it leaves running
+//! killed.
+
+use std::io;
+use std::io::fs;
+use std::io::fs::PathExtensions;
+use std::io::timer::Timer;
+use std::io::{File, IoResult, Open, Write};
+use std::time::Duration;
+
+use build::Job;
+
+/// How many times to try to remove a cgroup whose processes are still
+/// dying.
+static REMOVE_ATTEMPTS: uint = 50;
+
+/// Where to put builds' cgroups, and what to limit them to.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct CgroupSettings {
+    /// A cgroup that multibuilder can create cgroups in, e.g.
+    /// `/sys/fs/cgroup/multibuilder` delegated to its user.
+    pub parent: String,
+    /// How much memory each build may use, in bytes, beyond which
+    /// the whole build is killed. `None` for no limit.
+    pub memory_max: Option<u64>,
+}
+
+/// What a build used, as measured by its cgroup.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct Usage {
+    /// The most memory it used at once, in bytes.
+    pub peak_memory: Option<u64>,
+    /// The CPU time it used, in milliseconds.
+    pub cpu_ms: Option<u64>,
+}
+
+impl Usage {
+    /// As recorded in the history: `<peak_memory>/<cpu_ms>`, with
+    /// either left empty if it isn't known.
+    pub fn to_field(&self) -> String {
+        let show = |n: Option<u64>| n.map(|n| n.to_string()).unwrap_or(String::new());
+        format!("{}/{}", show(self.peak_memory), show(self.cpu_ms))
+    }
+
+    /// Parse `to_field`. `None` if it's empty.
+    pub fn from_field(field: &str) -> Option<Usage> {
+        if field.is_empty() {
+            return None
+        }
+        let mut parts = field.splitn('/', 1);
+        let peak_memory = parts.next().and_then(from_str);
+        let cpu_ms = parts.next().and_then(from_str);
+        Some(Usage { peak_memory: peak_memory, cpu_ms: cpu_ms })
+    }
+
+    /// Peak memory and CPU time, with `?` for what wasn't measured.
+    pub fn describe(&self) -> String {
+        let memory = match self.peak_memory {
+            Some(bytes) => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+            None => "?".to_string()
+        };
+        let cpu = match self.cpu_ms {
+            Some(ms) => format!("{}.{:03}s", ms / 1000, ms % 1000),
+            None => "?".to_string()
+        };
+        format!("peak memory {}, CPU time {}", memory, cpu)
+    }
+}
+
+pub struct Cgroup {
+    path: Path,
+}
+
+impl Cgroup {
+    /// Create the cgroup for `job`.
+    pub fn create(settings: &CgroupSettings, job: &Job) -> IoResult<Cgroup> {
+        let parent = Path::new(settings.parent.as_slice());
+        // so that builds' cgroups get these controllers; if it fails,
+        // they might already have them.
+        let _ = write(&parent.join("cgroup.subtree_control"), "+memory +cpu");
+
+        let cgroup = Cgroup { path: parent.join(job.file_name()) };
+        if cgroup.path.exists() {
+            // left over from a build that was killed along with us.
+            cgroup.kill_all();
+            try!(cgroup.remove());
+        }
+        try!(fs::mkdir(&cgroup.path, io::USER_RWX));
+
+        match settings.memory_max {
+            Some(bytes) => {
+                try!(write(&cgroup.path.join("memory.max"), bytes.to_string().as_slice()));
+                // a build that's out of memory is dead, not just the
+                // process that happened to be chosen.
+                try!(write(&cgroup.path.join("memory.oom.group"), "1"));
+            }
+            None => {}
+        }
+        Ok(cgroup)
+    }
+
+    /// The command words to run a command in this cgroup: it moves
+    /// itself in before running anything, so that none of its children
+    /// can escape.
+    pub fn prefix(&self) -> Vec<String> {
+        vec!["/bin/sh".to_string(),
+             "-c".to_string(),
+             "echo $$ > \"$0\" && exec \"$@\"".to_string(),
+             format!("{}", self.path.join("cgroup.procs").display())]
+    }
+
+    /// What the build has used so far.
+    pub fn usage(&self) -> Usage {
+        let peak_memory = read(&self.path.join("memory.peak"))
+            .and_then(|s| from_str(s.as_slice().trim()));
+        let cpu_ms = read(&self.path.join("cpu.stat")).and_then(|s| {
+            s.as_slice().lines()
+                .filter_map(|l| {
+                    let mut words = l.words();
+                    match (words.next(), words.next()) {
+                        (Some("usage_usec"), Some(n)) => from_str::<u64>(n),
+                        _ => None
+                    }
+                })
+                .next()
+        }).map(|us| us / 1000);
+        Usage { peak_memory: peak_memory, cpu_ms: cpu_ms }
+    }
+
+    /// Kill everything still running in the cgroup, such as daemons
+    /// started by the build.
+    fn kill_all(&self) {
+        match write(&self.path.join("cgroup.kill"), "1") {
+            Ok(()) => {}
+            Err(e) => warn!("couldn't kill the processes in {}: {}", self.path.display(), e)
+        }
+    }
+
+    /// Kill anything left in the cgroup and remove it, returning what
+    /// the build used.
+    pub fn finish(self) -> Usage {
+        let usage = self.usage();
+        self.kill_all();
+        match self.remove() {
+            Ok(()) => {}
+            Err(e) => warn!("couldn't remove the cgroup {}: {}", self.path.display(), e)
+        }
+        usage
+    }
+
+    /// Remove the cgroup, giving whatever was killed in it a moment to
+    /// die first.
+    fn remove(&self) -> IoResult<()> {
+        let mut timer = Timer::new().ok().expect("No timer??");
+        let mut attempts = 0u;
+        loop {
+            match fs::rmdir(&self.path) {
+                // most likely still busy.
+                Err(_) if attempts < REMOVE_ATTEMPTS => {
+                    attempts += 1;
+                    timer.sleep(Duration::milliseconds(100));
+                }
+                result => return result
+            }
+        }
+    }
+}
+
+fn read(path: &Path) -> Option<String> {
+    File::open(path).read_to_string().ok()
+}
+
+fn write(path: &Path, value: &str) -> IoResult<()> {
+    File::open_mode(path, Open, Write).and_then(|mut f| f.write_str(value))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Usage;
+
+    #[test]
+    fn usage_field_round_trip() {
+        let usage = Usage { peak_memory: Some(3 * 1024 * 1024), cpu_ms: Some(1500) };
+        assert_eq!(usage.to_field(), "3145728/1500".to_string());
+        let parsed = Usage::from_field(usage.to_field().as_slice()).unwrap();
+        assert_eq!((parsed.peak_memory, parsed.cpu_ms), (Some(3145728), Some(1500)));
+        assert_eq!(parsed.describe(), "peak memory 3.0 MiB, CPU time 1.500s".to_string());
+    }
+
+    #[test]
+    fn usage_field_with_unknowns() {
+        let usage = Usage { peak_memory: None, cpu_ms: Some(20) };
+        assert_eq!(usage.to_field(), "/20".to_string());
+        let parsed = Usage::from_field("/20").unwrap();
+        assert_eq!((parsed.peak_memory, parsed.cpu_ms), (None, Some(20)));
+        assert_eq!(parsed.describe(), "peak memory ?, CPU time 0.020s".to_string());
+
+        assert!(Usage::from_field("").is_none());
+    }
+}
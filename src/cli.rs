@@ -0,0 +1,379 @@
+//! The subcommands for looking at and changing the history, rather
+//! than building. These talk to a running multibuilder via its
+//! control socket where they can, so that they don't trample on its
+//! view of the history.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::io::fs;
+use std::io::fs::PathExtensions;
+use std::io::{Append, File, Write};
+use std::{i64, str, uint};
+
+use serialize::json;
+
+use Config;
+use build::Job;
+use build_log::LogSettings;
+use commit_walker::CommitWalker;
+use control;
+use git::{Repo, Sha};
+use history;
+
+fn main_repo(config: &Config) -> Repo {
+    Repo::new(Path::new(config.main_repo.as_slice()))
+}
+
+fn resolve(repo: &Repo, rev: &str) -> Sha {
+    match repo.rev_parse(rev) {
+        Some(hash) => hash,
+        None => fail!("unknown revision `{}`", rev)
+    }
+}
+
+/// Send `line` to the running multibuilder, returning its reply.
+/// `None` if it isn't running, or has no control socket.
+fn ask_running(config: &Config, line: &str) -> Option<String> {
+    config.control_socket.as_ref()
+        .and_then(|p| control::request(&Path::new(p.as_slice()), line))
+}
+
+/// Print the reply to a command that did something. `false` if it
+/// failed.
+fn report(reply: &str) -> bool {
+    match json::decode::<control::Outcome>(reply) {
+        Ok(outcome) => {
+            if outcome.ok {
+                println!("{}", outcome.message);
+            } else {
+                println!("error: {}", outcome.message);
+            }
+            outcome.ok
+        }
+        Err(_) => {
+            println!("unexpected reply: {}", reply);
+            false
+        }
+    }
+}
+
+/// Ask the running multibuilder to build `revs` next. `false` if it
+/// isn't running.
+pub fn enqueue_running(config: &Config, revs: &[String]) -> bool {
+    if ask_running(config, "status").is_none() {
+        return false
+    }
+    for rev in revs.iter() {
+        match ask_running(config, format!("enqueue {}", rev).as_slice()) {
+            Some(reply) => { report(reply.as_slice()); }
+            None => fail!("lost the connection to the running multibuilder")
+        }
+    }
+    true
+}
+
+/// The commits whose most recent build (in any variant) failed.
+pub fn failures(history_path: &Path) -> Vec<String> {
+    let mut seen = HashSet::new();
+    history::latest(history::load(history_path).as_slice()).into_iter()
+        .filter(|e| e.is_failure() && seen.insert(e.hash.clone()))
+        .map(|e| e.hash.value)
+        .collect()
+}
+
+/// The build of `rev` in `variant`, or in the first variant if none
+/// is given.
+fn job(config: &Config, rev: &str, variant: Option<&str>) -> Job {
+    let hash = resolve(&main_repo(config), rev);
+    let variants = config.variant_names();
+    match variant {
+        None => Job::new(hash, variants[0].clone()),
+        Some(v) if variants.contains(&Some(v.to_string())) => Job::new(hash, Some(v.to_string())),
+        Some(v) => fail!("unknown variant `{}`", v)
+    }
+}
+
+/// Summarise the history, and what the running multibuilder (if any)
+/// is doing.
+pub fn status(config: &Config, history_path: &Path) {
+    let entries = history::latest(history::load(history_path).as_slice());
+    let mut counts: HashMap<String, uint> = HashMap::new();
+    for e in entries.iter() {
+        *counts.find_or_insert(e.status.clone(), 0) += 1;
+    }
+    let mut counts: Vec<(String, uint)> = counts.into_iter().collect();
+    counts.sort();
+    let counts: Vec<String> = counts.iter().map(|&(ref s, n)| format!("{} {}", n, s)).collect();
+    println!("{} builds in the history: {}", entries.len(), counts.connect(", "));
+
+    let reply = match ask_running(config, "status") {
+        None => { println!("Not running."); return }
+        Some(reply) => reply
+    };
+    let status = match json::decode::<control::Status>(reply.as_slice()) {
+        Ok(s) => s,
+        Err(_) => { println!("unexpected reply: {}", reply); return }
+    };
+
+    let state = if status.stopping {
+        " (stopping)"
+    } else if status.paused {
+        " (paused)"
+    } else {
+        ""
+    };
+    println!("Running{}, with {} workers:", state, status.workers.len());
+    for w in status.workers.iter() {
+        match w.building {
+            Some(ref hash) => println!("  {} ({}): building {}", w.id, w.name, hash),
+            None => println!("  {} ({}): idle", w.id, w.name),
+        }
+    }
+
+    match ask_running(config, "queue").and_then(|r| json::decode::<Vec<String>>(r.as_slice()).ok()) {
+        Some(queue) => println!("{} builds queued", queue.len()),
+        None => {}
+    }
+}
+
+/// Print the most recent result for each build in the history,
+/// optionally only failures and only those in the git revision range
+/// `range`.
+pub fn history(config: &Config, history_path: &Path, failed_only: bool, range: Option<&str>) {
+    let in_range: Option<HashSet<Sha>> = range.map(|r| {
+        match main_repo(config).rev_list(r) {
+            Some(hashes) => hashes.into_iter().collect(),
+            None => fail!("invalid revision range `{}`", r)
+        }
+    });
+
+    for e in history::latest(history::load(history_path).as_slice()).iter() {
+        if failed_only && !e.is_failure() {
+            continue
+        }
+        match in_range {
+            Some(ref hashes) if !hashes.contains(&e.hash) => continue,
+            _ => {}
+        }
+        let mut line = format!("{} {} {}", e.job().key(), e.status, e.built_by);
+        match e.duration {
+            Some(secs) => line.push_str(format!(" {}", format_duration(secs as f64)).as_slice()),
+            None => {}
+        }
+        match e.resources() {
+            Some(r) => line.push_str(format!(" ({})", r.describe()).as_slice()),
+            None => {}
+        }
+        println!("{}", line);
+    }
+}
+
+/// Remove `rev` from the history, so it will be built again.
+pub fn forget(config: &Config, history_path: &Path, rev: &str) {
+    match ask_running(config, format!("forget {}", rev).as_slice()) {
+        Some(reply) => { report(reply.as_slice()); }
+        None => {
+            // a multibuilder without a control socket would carry on
+            // appending to the file we replace, losing its results.
+            let _lock = match history::Lock::take(history_path) {
+                Ok(lock) => lock,
+                Err(pid) => fail!("a multibuilder (pid {}) is using {}; \
+                                   stop it first, or give it a `control_socket`",
+                                  pid, history_path.display())
+            };
+            let hash = resolve(&main_repo(config), rev);
+            if history::forget(history_path, &hash) {
+                println!("forgot {}", hash.value);
+            } else {
+                println!("{} isn't in the history", hash.value);
+            }
+        }
+    }
+}
+
+/// Print everything we know about the build(s) of `rev`.
+pub fn show(config: &Config, history_path: &Path, rev: &str) {
+    let repo = main_repo(config);
+    let hash = resolve(&repo, rev);
+    let subject = repo.exec("git", ["log".to_string(), "-1".to_string(),
+                                    "--format=%s".to_string(), hash.value.clone()]).output;
+    println!("{} {}", hash.value, str::from_utf8(subject.as_slice()).unwrap_or("").trim());
+
+    let entries: Vec<history::Entry> = history::load(history_path).into_iter()
+        .filter(|e| e.hash == hash)
+        .collect();
+    if entries.is_empty() {
+        println!("Never built.");
+    }
+    for e in entries.iter() {
+        let variant = e.variant.as_ref().map(|v| format!("{}: ", v)).unwrap_or(String::new());
+        match e.duration {
+            Some(secs) => println!("  {}{} ({}, {})", variant, e.status, e.built_by,
+                                   format_duration(secs as f64)),
+            None => println!("  {}{} ({})", variant, e.status, e.built_by),
+        }
+        match e.usage {
+            Some(ref usage) => println!("    {}", usage.describe()),
+            None => {}
+        }
+        for step in e.steps.iter() {
+            println!("    {}", step.describe());
+            match step.resources {
+                Some(ref r) => println!("      {}", r.describe()),
+                None => {}
+            }
+            match step.env {
+                Some(ref env) => {
+                    for (name, value) in env.iter() {
+                        println!("      {}={}", name, value);
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+
+    for variant in config.variant_names().into_iter() {
+        let job = Job::new(hash.clone(), variant);
+        match job.variant {
+            Some(ref v) => println!("{}:", v),
+            None => {}
+        }
+        show_files(config, &job);
+    }
+}
+
+/// Print where the build directory, log and artifacts of `job` are,
+/// and the log itself.
+fn show_files(config: &Config, job: &Job) {
+    let build_parent_dir = Path::new(config.build_parent_dir.as_slice());
+    let build_dir = build_parent_dir.join(job.file_name());
+    if build_dir.is_dir() {
+        println!("Build directory: {}", build_dir.display());
+    }
+
+    let log = log_path(config, job);
+    match File::open(&log).read_to_string() {
+        Ok(text) => {
+            println!("Log of the last local build ({}):", log.display());
+            print!("{}", text);
+        }
+        Err(_) => println!("No local log."),
+    }
+
+    match config.output {
+        None => {}
+        Some(ref output) => {
+            let dir = Path::new(output.parent_dir.as_slice()).join(job.key());
+            if !dir.is_dir() {
+                println!("No artifacts.");
+                return
+            }
+            println!("Artifacts in {}:", dir.display());
+            for p in fs::walk_dir(&dir).unwrap() {
+                if !p.is_dir() {
+                    println!("  {}", p.path_relative_from(&dir).unwrap_or(p.clone()).display());
+                }
+            }
+        }
+    }
+}
+
+/// Where the log of the last local build of `job` is.
+fn log_path(config: &Config, job: &Job) -> Path {
+    let logs = config.logs.clone().unwrap_or(LogSettings::default());
+    logs.path(&Path::new(config.build_parent_dir.as_slice()), job)
+}
+
+/// Print the log of `rev` (in `variant`, or the first variant), and if
+/// the running multibuilder is building it, keep printing what's added
+/// to it until it's finished.
+pub fn tail(config: &Config, rev: &str, variant: Option<&str>) {
+    let line = match variant {
+        Some(v) => format!("tail {} {}", rev, v),
+        None => format!("tail {}", rev),
+    };
+    let reader = config.control_socket.as_ref()
+        .and_then(|p| control::stream(&Path::new(p.as_slice()), line.as_slice()));
+    let mut reader = match reader {
+        Some(r) => r,
+        None => {
+            let log = log_path(config, &job(config, rev, variant));
+            match File::open(&log).read_to_string() {
+                Ok(text) => print!("{}", text),
+                Err(e) => println!("couldn't read {}: {}", log.display(), e),
+            }
+            return
+        }
+    };
+
+    let reply = match reader.read_line() {
+        Ok(l) => l,
+        Err(_) => fail!("lost the connection to the running multibuilder")
+    };
+    if json::decode::<control::Following>(reply.as_slice().trim()).is_err() {
+        report(reply.as_slice().trim());
+        return
+    }
+    for line in reader.lines() {
+        match line {
+            Ok(l) => print!("{}", l),
+            Err(_) => break
+        }
+    }
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs as i64;
+    if secs >= 3600 {
+        format!("{}h {}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Print the builds that running now would do, in order, and an
+/// estimate of how long that would take, without building anything.
+pub fn plan(config: &Config, history_path: &Path) {
+    let repo = main_repo(config);
+    let entries = history::load(history_path);
+
+    // only so the walker has somewhere to write: nothing is, and the
+    // history mustn't be touched (or created) by a dry run.
+    let file = File::open_mode(&Path::new("/dev/null"), Append, Write)
+        .ok().expect("Error opening /dev/null");
+    let variants = config.variant_names();
+    let walker = CommitWalker::new(&repo, history::done(history_path), file,
+                                   None, config.earliest_build, variants.clone());
+    let to_build = walker.upcoming(uint::MAX);
+
+    let earliest = config.earliest_build.unwrap_or(i64::MIN);
+    let in_range: Vec<(Sha, String)> = repo.first_parents(&resolve(&repo, "HEAD")).into_iter()
+        .take_while(|c| c.time >= earliest)
+        .map(|c| (c.hash, c.subject))
+        .collect();
+    let subjects: HashMap<Sha, String> = in_range.iter().map(|p| p.clone()).collect();
+
+    for job in to_build.iter() {
+        let subject = subjects.find(&job.hash).map(|s| s.as_slice()).unwrap_or("");
+        println!("{} {}", job.key(), subject);
+    }
+    println!("");
+    let in_range_builds = in_range.len() * variants.len();
+    // enqueued revisions can be outside the range.
+    let in_range_to_build = to_build.iter().filter(|j| subjects.contains_key(&j.hash)).count();
+    println!("{} builds to do ({} of the {} since earliest_build already done)",
+             to_build.len(), in_range_builds - in_range_to_build, in_range_builds);
+
+    match history::mean_duration(history::latest(entries.as_slice()).as_slice()) {
+        None => println!("No timed builds in the history to estimate from."),
+        Some(mean) => {
+            let builders = cmp::max(1, config.num_local_builders.unwrap_or_default());
+            let total = mean * to_build.len() as f64 / builders as f64;
+            println!("Estimated time: {} ({} per build, over {} local builders)",
+                     format_duration(total), format_duration(mean), builders);
+        }
+    }
+}
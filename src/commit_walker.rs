@@ -1,104 +1,247 @@
+use build::Job;
 use git::{Repo, Sha, RemoteBranch};
+use history;
 use std;
 use std::io::fs::File;
+use std::io::{Append, ReadWrite};
 use std::collections::HashSet;
 
 pub struct CommitWalker<'a> {
     repo: &'a Repo,
     next_candidate: Option<Sha>,
-    in_progress: HashSet<Sha>,
-    already_built: HashSet<Sha>,
+    /// builds to hand out before walking any further: those that were
+    /// handed out but never finished, and those asked for explicitly.
+    /// The last is handed out first.
+    queue: Vec<Job>,
+    in_progress: HashSet<Job>,
+    already_built: HashSet<Job>,
+    /// the variants each commit is built in (just `None` without a
+    /// build matrix).
+    variants: Vec<Option<String>>,
     already_built_file: File,
     pull_remote: Option<&'a RemoteBranch>,
     earliest_build: i64,
+    /// whether to (temporarily) not hand out any commits.
+    paused: bool,
 }
 
 impl<'r> CommitWalker<'r> {
     pub fn new<'a>(repo: &'a Repo,
-                   already_built: HashSet<Sha>, already_built_file: File,
-                   remote: Option<&'a RemoteBranch>, earliest_build: Option<i64>)
+                   already_built: HashSet<Job>, already_built_file: File,
+                   remote: Option<&'a RemoteBranch>, earliest_build: Option<i64>,
+                   variants: Vec<Option<String>>)
         -> CommitWalker<'a> {
         CommitWalker {
             repo: repo,
             next_candidate: Some(repo.rev_parse("HEAD").expect("Missing HEAD")),
+            queue: Vec::new(),
             in_progress: HashSet::new(),
             already_built: already_built,
+            variants: variants,
             already_built_file: already_built_file,
             pull_remote: remote,
             earliest_build: earliest_build.unwrap_or(std::num::Bounded::min_value()),
+            paused: false,
         }
     }
 
-    pub fn register_built(&mut self, hash: Sha, success: bool) {
-        self.in_progress.remove(&hash);
+    /// Record how the build `entry.job()` went. Unless it was
+    /// interrupted, it won't be handed out again.
+    pub fn register(&mut self, entry: history::Entry) {
+        let job = entry.job();
+        self.in_progress.remove(&job);
+        (writeln!(&mut self.already_built_file, "{}", entry.to_line())).unwrap();
 
-        let status = match success {
-            true => "success",
-            false => "failure",
-        };
-
-        (writeln!(&mut self.already_built_file, "{}:{}", hash.value, status)).unwrap();
-
-        self.already_built.insert(hash);
+        if entry.is_done() {
+            self.already_built.insert(job);
+        }
     }
 
-    pub fn find_unbuilt_commit(&mut self) -> Option<Sha> {
-        let CommitWalker {
-            ref repo,
-            ref mut next_candidate,
-            ref mut in_progress,
-            ref mut already_built,
-            ..
-        } = *self;
-
+    /// Each build of `hash`, one per variant.
+    pub fn jobs(&self, hash: &Sha) -> Vec<Job> {
+        self.variants.iter().map(|v| Job::new(hash.clone(), v.clone())).collect()
+    }
 
+    /// Pull from the remote (if any), and start walking from the new
+    /// HEAD if it moved. `true` if it did.
+    pub fn pull(&mut self) -> bool {
         match self.pull_remote {
             Some(r_b) => {
-                let old_head = repo.rev_parse("HEAD").expect("Missing current HEAD");
-                repo.pull(r_b);
-                let new_head = repo.rev_parse("HEAD").expect("Missing new HEAD");
+                let old_head = self.repo.rev_parse("HEAD").expect("Missing current HEAD");
+                self.repo.pull(r_b);
+                let new_head = self.repo.rev_parse("HEAD").expect("Missing new HEAD");
                 if new_head != old_head {
-                    *next_candidate = Some(new_head);
+                    self.next_candidate = Some(new_head);
+                    return true
                 }
+                false
+            }
+            None => false
+        }
+    }
+
+    /// Record that `job` shouldn't be built, at the request of `by`.
+    /// It counts as built from then on.
+    pub fn skip(&mut self, job: Job, by: &str) {
+        self.queue.retain(|j| *j != job);
+        self.register(history::Entry {
+            hash: job.hash,
+            variant: job.variant,
+            status: "skipped".to_string(),
+            built_by: by.to_string(),
+            duration: None,
+            usage: None,
+            steps: Vec::new(),
+        });
+    }
+
+    /// Give up on `job` that was in progress (e.g. its worker
+    /// disappeared), so that it is returned by `find_unbuilt_job`
+    /// again.
+    pub fn requeue(&mut self, job: Job) {
+        if self.in_progress.remove(&job) {
+            self.queue.push(job);
+        }
+    }
+
+    /// Build `hash` (in every variant) once the builds already queued
+    /// have been handed out, whether or not it has been built before.
+    pub fn enqueue(&mut self, hash: Sha) {
+        for job in self.jobs(&hash).into_iter().rev() {
+            self.already_built.remove(&job);
+            if !self.queue.contains(&job) {
+                self.queue.insert(0, job);
             }
-            None => {}
         }
+    }
+
+    /// The builds of `hash` that are in progress.
+    pub fn in_progress(&self, hash: &Sha) -> Vec<Job> {
+        self.jobs(hash).into_iter().filter(|j| self.in_progress.contains(j)).collect()
+    }
+
+    /// The builds of `hash` that haven't been done, and aren't in
+    /// progress.
+    pub fn unbuilt(&self, hash: &Sha) -> Vec<Job> {
+        self.jobs(hash).into_iter()
+            .filter(|j| !self.already_built.contains(j) && !self.in_progress.contains(j))
+            .collect()
+    }
+
+    /// Remove every record of `hash` from the history, so that it
+    /// will be built when it is next walked past (e.g. next run).
+    /// `false` if it wasn't there.
+    pub fn forget(&mut self, hash: &Sha) -> bool {
+        let path = self.already_built_file.path().clone();
+        if !history::forget(&path, hash) {
+            return false
+        }
+        // the old file has been replaced.
+        self.already_built_file = File::open_mode(&path, Append, ReadWrite)
+            .ok().expect(format!("couldn't reopen {}", path.display()).as_slice());
+
+        self.already_built.retain(|j| j.hash != *hash);
+        true
+    }
 
-        match next_candidate.take() {
-            None => None,
-            Some(hash) => {
-                let mut hash = hash;
+    /// Up to `limit` of the builds that `find_unbuilt_job` will
+    /// return next (if nothing changes in the meantime), in order.
+    pub fn upcoming(&self, limit: uint) -> Vec<Job> {
+        let mut result = Vec::new();
+        let mut seen = HashSet::new();
+        for job in self.queue.iter().rev() {
+            if result.len() == limit {
+                return result
+            }
+            if !self.already_built.contains(job) && seen.insert(job.clone()) {
+                result.push(job.clone());
+            }
+        }
 
-                if self.earliest_build > repo.ctime(&hash).unwrap() {
-                    info!("Next candidate is too old, not building");
-                    *next_candidate = None;
-                    return None;
+        let start = match self.next_candidate {
+            None => return result,
+            Some(ref h) => h
+        };
+        for commit in self.repo.first_parents(start).into_iter() {
+            if self.earliest_build > commit.time {
+                break
+            }
+            for job in self.unbuilt(&commit.hash).into_iter() {
+                if result.len() == limit {
+                    return result
+                }
+                if !seen.contains(&job) {
+                    result.push(job);
                 }
+            }
+        }
+        result
+    }
+
+    /// Only hand out commits that are queued, rather than walking
+    /// back through the history.
+    pub fn stop_walking(&mut self) {
+        self.next_candidate = None;
+    }
+
+    /// Stop `find_unbuilt_job` returning anything until `resume` is
+    /// called.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// The next build to do, if there is one (and we're not paused).
+    pub fn find_unbuilt_job(&mut self) -> Option<Job> {
+        if self.paused {
+            return None;
+        }
 
-                loop {
-                    let parent = repo.parent_commit(&hash);
-                    if self.earliest_build > repo.ctime(&hash).unwrap() {
-                        info!("Parent too old, not building");
-                        *next_candidate = None;
-                        return None;
-                    }
-
-                    // not built, and not in progress.
-                    if !already_built.contains(&hash) && !in_progress.contains(&hash) {
-                        *next_candidate = parent;
-                        in_progress.insert(hash.clone());
-                        return Some(hash);
-                    }
-
-                    match parent {
-                        None => {
-                            *next_candidate = None;
-                            return None;
-                        }
-                        Some(h) => hash = h
-                    }
+        loop {
+            match self.queue.pop() {
+                None => break,
+                // it might've been built by someone else in the
+                // meantime.
+                Some(job) => if !self.already_built.contains(&job) &&
+                                !self.in_progress.contains(&job) {
+                    self.in_progress.insert(job.clone());
+                    return Some(job);
                 }
             }
         }
+
+        loop {
+            let hash = match self.next_candidate.take() {
+                None => return None,
+                Some(hash) => hash
+            };
+            if self.earliest_build > self.repo.ctime(&hash).unwrap() {
+                info!("Next candidate is too old, not building");
+                return None;
+            }
+
+            // not built, and not in progress: stay on this commit
+            // until each of its variants has been handed out.
+            let mut unbuilt = self.unbuilt(&hash);
+            if unbuilt.is_empty() {
+                self.next_candidate = self.repo.parent_commit(&hash);
+                continue
+            }
+            let job = unbuilt.remove(0).unwrap();
+            self.next_candidate = if unbuilt.is_empty() {
+                self.repo.parent_commit(&hash)
+            } else {
+                Some(hash)
+            };
+            self.in_progress.insert(job.clone());
+            return Some(job);
+        }
     }
 }
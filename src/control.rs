@@ -0,0 +1,310 @@
+//! A local control socket, for asking a running multibuilder what
+//! it's doing and telling it to do things differently.
+//!
+//! The protocol is line-based: each line sent is a command, and each
+//! is answered with a single line of JSON. The commands are:
+//!
+//! - `status`: the workers and what they're building,
+//! - `queue`: the builds that will be done next, in order,
+//! - `results`: the most recently finished builds,
+//! - `enqueue <rev>`: build `<rev>` next (in every variant), even if it's
+//!   been built before,
+//! - `cancel <rev>`: don't build `<rev>` (in any variant), killing its
+//!   builds if they're running,
+//! - `forget <rev>`: remove `<rev>` from the history,
+//! - `pause`, `resume`: as for `SIGUSR1` and `SIGUSR2`,
+//! - `tail <rev> [<variant>]`: the local log of `<rev>` (in `<variant>`,
+//!   or the first variant, with a build matrix). The reply is followed
+//!   by the log as it is so far, and then each line as it's written
+//!   until the build finishes, when the connection is closed.
+//!
+//! Builds are named by their hash, or `<hash>/<variant>` with a build
+//! matrix.
+
+use std::io;
+use std::io::BufferedReader;
+use std::io::fs;
+use std::io::fs::PathExtensions;
+use std::io::net::pipe::{UnixListener, UnixStream};
+use std::io::timer::Timer;
+use std::io::{Acceptor, File, IoResult, Listener};
+use std::task;
+use std::time::Duration;
+
+use serialize::json;
+
+use coordinator;
+use coordinator::Event;
+
+/// A command received on the control socket.
+#[deriving(Show)]
+pub enum Request {
+    Status,
+    Queue,
+    Results,
+    Enqueue(String),
+    Cancel(String),
+    Forget(String),
+    Pause,
+    Resume,
+    /// The revision, and the variant (if given).
+    Tail(String, Option<String>),
+}
+
+impl Request {
+    /// Parse a line sent to the control socket.
+    pub fn parse(line: &str) -> Result<Request, String> {
+        let mut words = line.words();
+        let command = match words.next() {
+            Some(c) => c,
+            None => return Err("empty command".to_string())
+        };
+        let rev = words.next();
+        let variant = if command == "tail" { words.next() } else { None };
+        if words.next().is_some() {
+            return Err(format!("too many arguments to `{}`", command));
+        }
+        // anything else would be taken as an option by git.
+        match rev {
+            Some(r) if r.starts_with("-") => return Err(format!("invalid revision `{}`", r)),
+            _ => {}
+        }
+
+        match (command, rev) {
+            ("status", None) => Ok(Status),
+            ("queue", None) => Ok(Queue),
+            ("results", None) => Ok(Results),
+            ("pause", None) => Ok(Pause),
+            ("resume", None) => Ok(Resume),
+            ("enqueue", Some(r)) => Ok(Enqueue(r.to_string())),
+            ("cancel", Some(r)) => Ok(Cancel(r.to_string())),
+            ("forget", Some(r)) => Ok(Forget(r.to_string())),
+            ("tail", Some(r)) => Ok(Tail(r.to_string(), variant.map(|v| v.to_string()))),
+            ("enqueue", None) | ("cancel", None) | ("forget", None) | ("tail", None) => {
+                Err(format!("`{}` needs a revision", command))
+            }
+            _ => Err(format!("unknown command `{}`", line))
+        }
+    }
+}
+
+/// A worker, as reported by `status`.
+#[deriving(Encodable, Decodable)]
+pub struct WorkerStatus {
+    pub id: uint,
+    pub name: String,
+    /// The build it is doing, if any.
+    pub building: Option<String>,
+}
+
+/// The reply to `status`.
+#[deriving(Encodable, Decodable)]
+pub struct Status {
+    pub paused: bool,
+    pub stopping: bool,
+    pub workers: Vec<WorkerStatus>,
+}
+
+/// A finished build, as reported by `results`.
+#[deriving(Encodable, Decodable, Clone)]
+pub struct BuildRecord {
+    /// The hash, or `<hash>/<variant>`.
+    pub hash: String,
+    /// As recorded in the history: `success`, `failure`, ...
+    pub status: String,
+    pub built_by: String,
+    /// When it finished, as a unix timestamp.
+    pub finished_at: i64,
+}
+
+/// The reply to a command that does something, rather than asking
+/// about something.
+#[deriving(Encodable, Decodable)]
+pub struct Outcome {
+    pub ok: bool,
+    pub message: String,
+}
+
+/// The reply to `tail`, if there's a log to follow.
+#[deriving(Encodable, Decodable)]
+pub struct Following {
+    /// The build whose log it is, as in `WorkerStatus::building`.
+    pub hash: String,
+    pub log: String,
+}
+
+/// The reply to a command that succeeded.
+pub fn ok(message: &str) -> String {
+    json::encode(&Outcome { ok: true, message: message.to_string() })
+}
+
+/// The reply to a command that couldn't be done.
+pub fn error(message: &str) -> String {
+    json::encode(&Outcome { ok: false, message: message.to_string() })
+}
+
+/// Accept connections on a Unix socket at `path`, passing the
+/// commands received to the main task via `events`.
+pub fn listen(path: Path, events: Sender<Event>) {
+    // left behind by a previous run.
+    if path.exists() {
+        let _ = fs::unlink(&path);
+    }
+    let listener = UnixListener::bind(&path);
+    let mut acceptor = listener.listen().ok()
+        .expect(format!("couldn't listen on {}", path.display()).as_slice());
+    println!("Listening for control commands on {}", path.display());
+
+    task::spawn(proc() {
+        for stream in acceptor.incoming() {
+            match stream {
+                Ok(s) => {
+                    let events = events.clone();
+                    task::spawn(proc() serve(s, events));
+                }
+                Err(e) => warn!("accepting a control connection failed: {}", e)
+            }
+        }
+    });
+}
+
+/// Answer the commands on one control connection until it's closed.
+fn serve(stream: UnixStream, events: Sender<Event>) {
+    let mut writer = stream.clone();
+    let mut reader = BufferedReader::new(stream);
+
+    for line in reader.lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(_) => break
+        };
+
+        let request = match Request::parse(line.as_slice().trim()) {
+            Err(e) => {
+                if writer.write_line(error(e.as_slice()).as_slice()).is_err() {
+                    break
+                }
+                continue
+            }
+            Ok(request) => request
+        };
+        debug!("control request: {}", request);
+        let tailing = match request { Tail(..) => true, _ => false };
+        let reply = match ask(&events, request) {
+            Some(reply) => reply,
+            None => break
+        };
+
+        if writer.write_line(reply.as_slice()).is_err() {
+            break
+        }
+        if tailing {
+            match json::decode::<Following>(reply.as_slice()) {
+                Ok(following) => {
+                    match follow(&mut writer, &following, &events) {
+                        Ok(()) => {}
+                        Err(e) => debug!("stopped following {}: {}", following.log, e)
+                    }
+                    break
+                }
+                // it was an error instead.
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+/// Pass `request` to the main task, returning its reply. `None` if
+/// it's gone.
+fn ask(events: &Sender<Event>, request: Request) -> Option<String> {
+    let (tx, rx) = channel();
+    if events.send_opt(coordinator::Control(request, tx)).is_err() {
+        return None
+    }
+    rx.recv_opt().ok()
+}
+
+/// Write the log in `following` to `writer`, as it is and then as it
+/// grows, until its build is no longer running.
+fn follow(writer: &mut UnixStream, following: &Following, events: &Sender<Event>) -> IoResult<()> {
+    let mut file = try!(File::open(&Path::new(following.log.as_slice())));
+    let mut timer = Timer::new().ok().expect("No timer??");
+    let mut buf = [0u8, ..4096];
+    loop {
+        // checked before reading, so that nothing written between the
+        // two is missed.
+        let building = is_building(events, following.hash.as_slice());
+        loop {
+            match file.read(buf.as_mut_slice()) {
+                Ok(n) => try!(writer.write(buf.slice_to(n))),
+                Err(ref e) if e.kind == io::EndOfFile => break,
+                Err(e) => return Err(e)
+            }
+        }
+        if !building {
+            return Ok(())
+        }
+        timer.sleep(Duration::milliseconds(500));
+    }
+}
+
+fn is_building(events: &Sender<Event>, hash: &str) -> bool {
+    match ask(events, Status).and_then(|r| json::decode::<Status>(r.as_slice()).ok()) {
+        Some(status) => {
+            status.workers.iter().any(|w| w.building.as_ref().map(|h| h.as_slice()) == Some(hash))
+        }
+        None => false
+    }
+}
+
+/// Send `line` to the multibuilder listening at `path`, returning the
+/// connection to read the reply from. `None` if there's nothing
+/// listening there.
+pub fn stream(path: &Path, line: &str) -> Option<BufferedReader<UnixStream>> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(s) => s,
+        Err(_) => return None
+    };
+    if stream.write_line(line).is_err() {
+        return None
+    }
+    Some(BufferedReader::new(stream))
+}
+
+/// Send a single command to the multibuilder listening at `path`,
+/// returning its reply. `None` if there's nothing listening there.
+pub fn request(path: &Path, line: &str) -> Option<String> {
+    stream(path, line)
+        .and_then(|mut reader| reader.read_line().ok())
+        .map(|l| l.as_slice().trim().to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Request, Status, Cancel, Tail};
+
+    #[test]
+    fn parse_commands() {
+        match Request::parse("status") { Ok(Status) => {} r => fail!("{}", r) }
+        match Request::parse("  cancel  abc123 ") {
+            Ok(Cancel(ref rev)) if rev.as_slice() == "abc123" => {}
+            r => fail!("{}", r)
+        }
+        match Request::parse("tail abc123") {
+            Ok(Tail(ref rev, None)) if rev.as_slice() == "abc123" => {}
+            r => fail!("{}", r)
+        }
+        match Request::parse("tail abc123 debug") {
+            Ok(Tail(ref rev, Some(ref v))) if rev.as_slice() == "abc123" && v.as_slice() == "debug" => {}
+            r => fail!("{}", r)
+        }
+    }
+
+    #[test]
+    fn parse_rejects_bad_commands() {
+        for line in ["", "frobnicate", "cancel", "status abc123", "cancel a b",
+                     "cancel --all", "tail abc123 debug extra"].iter() {
+            assert!(Request::parse(*line).is_err(), "{} parsed", line);
+        }
+    }
+}
@@ -0,0 +1,588 @@
+//! The main loop: handing out commits to workers and dealing with the
+//! results, driven by a single channel of `Event`s from the workers
+//! and everything else that might give us something to do.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet, TreeMap};
+use std::io::fs::PathExtensions;
+use std::io::timer::Timer;
+use std::os;
+use std::sync::Arc;
+use std::task;
+use std::time::Duration;
+
+use serialize::json;
+use term;
+use time;
+
+use {Command, Config, Variant};
+use build;
+use build::{BuildResult, Job};
+use build_log::LogSettings;
+use commit_walker::CommitWalker;
+use control;
+use control::{BuildRecord, Request};
+use git::{RemoteBranch, Repo, Sha};
+use history;
+use output;
+use signals::{ChildProcesses, Signal, Interrupt, Terminate, HangUp, User1, User2};
+use task_worker;
+use task_worker::TaskWorker;
+
+/// How long to wait between fetches of `pull_from` when they keep
+/// failing, at most, as a multiple of the normal interval.
+static MAX_BACKOFF_FACTOR: i64 = 16;
+
+/// How long builds get to finish after `SIGTERM` before being killed,
+/// when we're interrupted twice.
+static DEFAULT_SHUTDOWN_GRACE_SECONDS: i64 = 10;
+
+/// How many finished builds the control socket's `results` reports.
+static RECENT_RESULTS: uint = 50;
+
+/// How many upcoming builds the control socket's `queue` reports.
+static QUEUE_LENGTH: uint = 100;
+
+/// Something that the main loop needs to react to.
+pub enum Event {
+    /// The worker with the given id finished a build.
+    Finished(uint, BuildResult),
+    /// The worker with the given id died or disconnected.
+    Lost(uint),
+    /// A remote worker connected, and was given the id.
+    Connected(uint, TaskWorker),
+    /// There are new commits on the remote we're pulling from.
+    RemoteUpdated,
+    /// We received the given signal.
+    Signalled(Signal),
+    /// A command from the control socket, to be answered down the
+    /// `Sender`.
+    Control(Request, Sender<String>),
+}
+
+/// Fetch `remote` into `repo` every `interval_secs`, sending
+/// `RemoteUpdated` down `events` whenever it has moved. Failed fetches
+/// are retried with exponential backoff.
+pub fn watch_remote(repo: Repo, remote: RemoteBranch, interval_secs: i64, events: Sender<Event>) {
+    task::spawn(proc() {
+        let remote_ref = format!("{}/{}", remote.name, remote.branch);
+        let mut timer = Timer::new().ok().expect("No timer??");
+        let mut last_seen = None;
+        let mut delay = interval_secs;
+        loop {
+            if repo.fetch(&remote) {
+                delay = interval_secs;
+                let head = repo.rev_parse(remote_ref.as_slice());
+                if head.is_some() && head != last_seen {
+                    debug!("{} is now {}", remote_ref, head);
+                    last_seen = head;
+                    if events.send_opt(RemoteUpdated).is_err() {
+                        break
+                    }
+                }
+            } else {
+                delay = cmp::min(delay * 2, interval_secs * MAX_BACKOFF_FACTOR);
+                warn!("fetching {} failed, trying again in {}s", remote_ref, delay);
+            }
+            timer.sleep(Duration::seconds(delay));
+        }
+    });
+}
+
+pub struct Coordinator<'a> {
+    config: &'a Config,
+    walker: CommitWalker<'a>,
+    events: Receiver<Event>,
+    /// for handing to new local workers.
+    events_tx: Sender<Event>,
+    /// All the workers we have, busy or not, by id.
+    workers: HashMap<uint, TaskWorker>,
+    build_dir: Path,
+    main_repo: Arc<Repo>,
+    build_commands: Arc<Vec<Command>>,
+    variants: Arc<Vec<Variant>>,
+    /// Whether remote workers might connect.
+    listening: bool,
+    /// Whether to keep going after running out of commits.
+    daemon: bool,
+    /// Whether anything has been built since `when_finished` was last
+    /// run.
+    batch_started: bool,
+    /// The processes started by local builds.
+    children: Arc<ChildProcesses>,
+    /// Whether we've been asked to stop, and so shouldn't start any
+    /// more builds.
+    stopping: bool,
+    /// Whether we've been asked to stop *now*, and so have killed the
+    /// running builds.
+    killing: bool,
+    /// Builds that are being killed at the request of the control
+    /// socket.
+    cancelled: HashSet<Job>,
+    /// The most recently finished builds, oldest first.
+    recent: Vec<BuildRecord>,
+}
+
+impl<'a> Coordinator<'a> {
+    pub fn new(config: &'a Config,
+               walker: CommitWalker<'a>,
+               events_tx: Sender<Event>,
+               events: Receiver<Event>,
+               build_dir: Path,
+               main_repo: Arc<Repo>,
+               listening: bool,
+               daemon: bool) -> Coordinator<'a> {
+        let mut coordinator = Coordinator {
+            config: config,
+            walker: walker,
+            events: events,
+            events_tx: events_tx,
+            workers: HashMap::new(),
+            build_dir: build_dir,
+            main_repo: main_repo,
+            build_commands: Arc::new(config.build_commands.clone()),
+            variants: Arc::new(config.variants.clone().unwrap_or(Vec::new())),
+            listening: listening,
+            daemon: daemon,
+            batch_started: false,
+            children: Arc::new(ChildProcesses::new()),
+            stopping: false,
+            killing: false,
+            cancelled: HashSet::new(),
+            recent: Vec::new(),
+        };
+
+        for _ in range(0, config.num_local_builders.unwrap_or_default()) {
+            coordinator.spawn_local();
+        }
+        coordinator
+    }
+
+    fn spawn_local(&mut self) {
+        let id = task_worker::next_id();
+        let worker = TaskWorker::new(id,
+                                     self.events_tx.clone(),
+                                     self.build_dir.clone(),
+                                     self.main_repo.clone(),
+                                     self.build_commands.clone(),
+                                     self.variants.clone(),
+                                     self.config.build_settings(),
+                                     self.config.output.as_ref()
+                                         .map(|o| Path::new(o.parent_dir.as_slice())),
+                                     self.children.clone());
+        self.workers.insert(id, worker);
+    }
+
+    /// Build things until there's nothing left to build; or, as a
+    /// daemon, forever; or until we're told to stop.
+    pub fn run(&mut self) {
+        loop {
+            if self.stopping {
+                // remote builds can't be killed from here, so don't
+                // wait for them once we're killing: they just won't
+                // be recorded, and so will be done again next time.
+                let killing = self.killing;
+                let waiting = self.workers.values()
+                    .filter(|w| w.current.is_some() && !(killing && w.remote.is_some()))
+                    .count();
+                if waiting == 0 {
+                    println!("Stopped.");
+                    return
+                }
+            } else {
+                self.schedule();
+            }
+
+            // being paused isn't the same as running out of things to
+            // build.
+            let busy = self.workers.values().any(|w| w.current.is_some());
+            if !self.stopping && !busy && !self.walker.is_paused() &&
+                    (!self.listening || self.out_of_work()) {
+                if !self.daemon {
+                    break
+                }
+                if self.batch_started {
+                    self.batch_started = false;
+                    self.when_finished();
+                    println!("Waiting for new commits");
+                }
+            }
+
+            match self.events.recv() {
+                Finished(id, result) => self.finished(id, result),
+                Lost(id) => self.lost(id),
+                Connected(id, worker) => {
+                    self.workers.insert(id, worker);
+                }
+                RemoteUpdated => {
+                    if self.walker.pull() {
+                        info!("Pulled new commits");
+                    }
+                }
+                Signalled(signal) => self.signalled(signal),
+                Control(request, reply) => {
+                    let answer = self.control(request);
+                    let _ = reply.send_opt(answer);
+                }
+            }
+        }
+
+        self.when_finished();
+    }
+
+    fn when_finished(&self) {
+        info!("No more builds, running when_finished");
+        let base = self.hook_env();
+        for cmd in self.config.when_finished.iter() {
+            debug!("Running {}", cmd);
+            let env = cmd.env(&base, |s| s.to_string());
+            let result = cmd.process(&os::getcwd(), &env, [], |s| s.to_string(), |s| s.to_string())
+                .output()
+                .unwrap();
+
+            if !result.status.success() {
+                error!("{} failed", cmd.line());
+            }
+        }
+    }
+
+    /// The environment `when_finished` commands run in: ours, and a
+    /// few variables of their own.
+    fn hook_env(&self) -> TreeMap<String, String> {
+        let output = self.config.output.as_ref()
+            .map(|o| o.parent_dir.clone())
+            .unwrap_or(String::new());
+        let mut env: TreeMap<String, String> = os::env().into_iter().collect();
+        env.insert("MULTIBUILDER_REPO".to_string(), format!("{}", self.main_repo.path.display()));
+        env.insert("MULTIBUILDER_BUILD_PARENT_DIR".to_string(),
+                   format!("{}", self.build_dir.display()));
+        env.insert("MULTIBUILDER_OUTPUT_PARENT_DIR".to_string(), output);
+        env
+    }
+
+    /// Give every idle worker something to do, if there's anything
+    /// to do.
+    fn schedule(&mut self) {
+        let idle: Vec<uint> = self.workers.iter()
+            .filter(|&(_, w)| w.current.is_none())
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in idle.into_iter() {
+            match self.walker.find_unbuilt_job() {
+                None => { debug!("No more commits to build"); break }
+                Some(job) => {
+                    info!("Sending {} to worker {}", job.key(), id);
+                    self.workers.find_mut(&id).unwrap().send(build::BuildJob(job));
+                    self.batch_started = true;
+                }
+            }
+        }
+    }
+
+    /// The first `SIGINT`/`SIGTERM` (or `SIGHUP`) lets the running
+    /// builds finish; the second kills them. `SIGUSR1` and `SIGUSR2`
+    /// pause and resume handing out new builds.
+    fn signalled(&mut self, signal: Signal) {
+        match signal {
+            User1 => { self.pause(); return }
+            User2 => { self.resume(); return }
+            Interrupt | Terminate | HangUp => {}
+        }
+
+        if !self.stopping {
+            self.stopping = true;
+            let busy = self.workers.values().filter(|w| w.current.is_some()).count();
+            println!("Stopping: waiting for {} running builds to finish \
+                      (interrupt again to kill them).", busy);
+        } else if !self.killing {
+            self.killing = true;
+            println!("Killing running builds.");
+            let children = self.children.clone();
+            let grace = self.config.shutdown_grace_seconds.unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECONDS);
+            task::spawn(proc() children.kill_all(grace));
+        }
+    }
+
+    /// Stop handing out builds, letting those in progress finish.
+    pub fn pause(&mut self) {
+        if !self.walker.is_paused() {
+            self.walker.pause();
+            println!("Paused.");
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if self.walker.is_paused() {
+            self.walker.resume();
+            println!("Resumed.");
+        }
+    }
+
+    /// Carry out a command from the control socket, returning the
+    /// reply.
+    fn control(&mut self, request: Request) -> String {
+        match request {
+            control::Status => {
+                let mut workers: Vec<control::WorkerStatus> = self.workers.iter()
+                    .map(|(id, w)| control::WorkerStatus {
+                        id: *id,
+                        name: w.name().to_string(),
+                        building: w.current.as_ref().map(|j| j.key()),
+                    })
+                    .collect();
+                workers.sort_by(|a, b| a.id.cmp(&b.id));
+                json::encode(&control::Status {
+                    paused: self.walker.is_paused(),
+                    stopping: self.stopping,
+                    workers: workers,
+                })
+            }
+            control::Queue => {
+                let queue: Vec<String> = self.walker.upcoming(QUEUE_LENGTH).into_iter()
+                    .map(|j| j.key())
+                    .collect();
+                json::encode(&queue)
+            }
+            control::Results => json::encode(&self.recent),
+            control::Pause => { self.pause(); control::ok("paused") }
+            control::Resume => { self.resume(); control::ok("resumed") }
+            control::Enqueue(rev) => match self.main_repo.rev_parse(rev.as_slice()) {
+                None => control::error(format!("unknown revision `{}`", rev).as_slice()),
+                Some(hash) => {
+                    println!("Queued {}.", hash.value);
+                    let msg = format!("queued {}", hash.value);
+                    self.walker.enqueue(hash);
+                    control::ok(msg.as_slice())
+                }
+            },
+            control::Cancel(rev) => match self.main_repo.rev_parse(rev.as_slice()) {
+                None => control::error(format!("unknown revision `{}`", rev).as_slice()),
+                Some(hash) => self.cancel(hash),
+            },
+            control::Forget(rev) => match self.main_repo.rev_parse(rev.as_slice()) {
+                None => control::error(format!("unknown revision `{}`", rev).as_slice()),
+                Some(hash) => {
+                    if self.walker.forget(&hash) {
+                        println!("Forgot {}.", hash.value);
+                        control::ok(format!("forgot {}", hash.value).as_slice())
+                    } else {
+                        control::error(format!("{} isn't in the history", hash.value).as_slice())
+                    }
+                }
+            },
+            control::Tail(rev, variant) => match self.main_repo.rev_parse(rev.as_slice()) {
+                None => control::error(format!("unknown revision `{}`", rev).as_slice()),
+                Some(hash) => self.tail(hash, variant),
+            },
+        }
+    }
+
+    /// Make sure `hash` doesn't get built, in any variant: kill the
+    /// builds of it running here, and record the others as cancelled
+    /// so they're skipped.
+    fn cancel(&mut self, hash: Sha) -> String {
+        let running = self.walker.in_progress(&hash);
+        let remote = self.workers.values()
+            .any(|w| w.current.as_ref().map_or(false, |j| running.contains(j)) &&
+                     w.remote.is_some());
+        if remote {
+            return control::error(format!("{} is being built by a remote worker, \
+                                           which can't be stopped from here",
+                                          hash.value).as_slice());
+        }
+
+        let mut done = Vec::new();
+        if !running.is_empty() {
+            println!("Cancelling the build of {}.", hash.value);
+            for job in running.iter() {
+                self.cancelled.insert(job.clone());
+            }
+            // marked killed straight away, so that nothing more of
+            // them starts, even if nothing's running right now.
+            self.children.kill_building(running.as_slice());
+            let children = self.children.clone();
+            let grace = self.config.shutdown_grace_seconds.unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECONDS);
+            let killing = running.clone();
+            task::spawn(proc() children.force_kill(killing.as_slice(), grace));
+            done.push(format!("killing the build of {}", hash.value));
+        }
+        let skipping: Vec<Job> = self.walker.jobs(&hash).into_iter()
+            .filter(|j| !running.contains(j))
+            .collect();
+        if !skipping.is_empty() {
+            println!("Skipping {}.", hash.value);
+            done.push(format!("skipping {}", hash.value));
+        }
+        for job in skipping.into_iter() {
+            self.walker.skip(job, "control");
+        }
+        control::ok(done.connect(", ").as_slice())
+    }
+
+    /// Where to follow the log of `hash` (in `variant`, or the first
+    /// variant) from, if it's here.
+    fn tail(&self, hash: Sha, variant: Option<String>) -> String {
+        let variants = self.config.variant_names();
+        let variant = match variant {
+            None => variants[0].clone(),
+            Some(v) => {
+                if !variants.contains(&Some(v.clone())) {
+                    return control::error(format!("unknown variant `{}`", v).as_slice());
+                }
+                Some(v)
+            }
+        };
+        let job = Job::new(hash, variant);
+
+        let remote = self.workers.values()
+            .any(|w| w.current.as_ref() == Some(&job) && w.remote.is_some());
+        if remote {
+            return control::error(format!("{} is being built by a remote worker, \
+                                           which has its log", job.key()).as_slice());
+        }
+
+        let logs = self.config.logs.clone().unwrap_or(LogSettings::default());
+        let log = logs.path(&self.build_dir, &job);
+        if !log.exists() {
+            return control::error(format!("there's no log of {}", job.key()).as_slice());
+        }
+        json::encode(&control::Following {
+            hash: job.key(),
+            log: format!("{}", log.display()),
+        })
+    }
+
+    fn remember(&mut self, job: &Job, status: &str, built_by: &str) {
+        self.recent.push(BuildRecord {
+            hash: job.key(),
+            status: status.to_string(),
+            built_by: built_by.to_string(),
+            finished_at: time::get_time().sec,
+        });
+        if self.recent.len() > RECENT_RESULTS {
+            self.recent.remove(0);
+        }
+    }
+
+    /// Whether there is definitely nothing left to build (for now).
+    fn out_of_work(&mut self) -> bool {
+        match self.walker.find_unbuilt_job() {
+            None => true,
+            Some(job) => {
+                // just looking.
+                self.walker.requeue(job);
+                false
+            }
+        }
+    }
+
+    fn finished(&mut self, id: uint, result: BuildResult) {
+        let (built_by, duration) = match self.workers.find_mut(&id) {
+            Some(worker) => {
+                if worker.current.as_ref() != Some(&result.job) {
+                    warn!("{} reported on {}, which it wasn't building; ignoring it",
+                          worker.name(), result.job.key());
+                    return
+                }
+                worker.current = None;
+                (worker.name().to_string(), Some(time::get_time().sec - worker.started))
+            }
+            // e.g. its lease expired just before it reported back; the
+            // build still happened.
+            None => ("unknown".to_string(), None)
+        };
+
+        let BuildResult { job, outcome, steps, usage } = result;
+        let cancelled = self.cancelled.remove(&job);
+        self.children.forget_killed(&job);
+        let outcome = match outcome {
+            // finished before it could be killed.
+            build::Success(loc) => build::Success(loc),
+            // we killed it, so it doesn't count.
+            _ if cancelled => build::Cancelled,
+            build::Failure if self.killing => build::Interrupted,
+            outcome => outcome,
+        };
+        let status = outcome.status();
+
+        let mut term = term::stdout().unwrap();
+        match outcome {
+            // \o/ we won!
+            build::Success(loc) => {
+                term.fg(term::color::GREEN).unwrap();
+                println!("{} succeeded.", job.key());
+                term.reset().unwrap();
+
+                match (&self.config.output, loc) {
+                    (&None, _) => {}
+                    (&Some(ref output), build::Local(p)) => output.move_local(&job, &p),
+                    (&Some(_), build::Remote(worker, _)) => {
+                        // the connection already received the
+                        // artifacts and put them in place.
+                        debug!("output of {} uploaded by {}", job.key(), worker);
+                    }
+                }
+            }
+            // it was the crushing disappointment of failure. :(
+            build::Failure => {
+                term.fg(term::color::RED).unwrap();
+                println!("{} failed.", job.key());
+                term.reset().unwrap();
+            }
+            // not the commit's fault, at least not obviously.
+            build::TimedOut | build::Infrastructure => {
+                term.fg(term::color::YELLOW).unwrap();
+                println!("{} {}.", job.key(), status);
+                term.reset().unwrap();
+                output::remove_build_dir(&self.build_dir.join(job.file_name()));
+            }
+            build::Cancelled | build::Interrupted | build::Skipped => {
+                println!("{} {}.", job.key(), status);
+                output::remove_build_dir(&self.build_dir.join(job.file_name()));
+            }
+        }
+        for step in steps.iter().filter(|s| !s.success()) {
+            println!("    {}", step.describe());
+        }
+        match usage {
+            Some(ref usage) => println!("    {}", usage.describe()),
+            None => {}
+        }
+
+        self.remember(&job, status, built_by.as_slice());
+        self.walker.register(history::Entry {
+            hash: job.hash,
+            variant: job.variant,
+            status: status.to_string(),
+            built_by: built_by,
+            duration: duration,
+            usage: usage,
+            steps: steps,
+        });
+    }
+
+    fn lost(&mut self, id: uint) {
+        let worker = match self.workers.pop(&id) {
+            Some(w) => w,
+            // already gone.
+            None => return
+        };
+
+        match worker.current {
+            None => {}
+            Some(ref job) => {
+                warn!("lost the worker building {}, requeueing", job.key());
+                self.walker.requeue(job.clone());
+            }
+        }
+
+        if worker.remote.is_none() && !self.stopping {
+            debug!("replacing local worker {}, it died", id);
+            self.spawn_local();
+        } else {
+            // it'll reconnect if it can.
+            debug!("removing remote worker {}, other end hung up", id);
+        }
+    }
+}
@@ -1,6 +1,13 @@
 use std::str;
+use std::task;
+use std::io::BufferedReader;
+use std::io::IoResult;
 use std::io::fs::PathExtensions;
-use std::io::process::{Command, ProcessOutput};
+use std::io::process::{Command, ProcessExit, ProcessOutput};
+
+use build::Job;
+use signals;
+use signals::ChildProcesses;
 
 /// Represents a git repository.
 #[deriving(Clone)]
@@ -15,11 +22,20 @@ pub struct RemoteBranch {
 }
 
 /// Represents a SHA hash used by git.
-#[deriving(Clone, PartialEq, Eq, Hash, Show)]
+#[deriving(Clone, PartialEq, Eq, Hash, Encodable, Decodable, Show)]
 pub struct Sha {
     pub value: String
 }
 
+/// A commit, as listed by `Repo::first_parents`.
+#[deriving(Clone, Show)]
+pub struct Commit {
+    pub hash: Sha,
+    /// The commit time, as a UNIX timestamp.
+    pub time: i64,
+    pub subject: String,
+}
+
 impl Repo {
     /// Pretend `p` is the path to a git repo. You better make sure it
     /// is.
@@ -27,31 +43,35 @@ impl Repo {
         Repo { path: p }
     }
 
-    /// "Clone" this repo into `dir`.
-    pub fn new_subrepo(&self, dir: Path) -> Repo {
+    /// "Clone" this repo into `dir` (or reuse what's already there) as
+    /// part of `job`, passing each line git writes to `output`, as for
+    /// `exec_streaming`. `Err` with a description of what went wrong
+    /// on failure.
+    pub fn new_subrepo(&self, dir: Path, children: &ChildProcesses, job: &Job,
+                       output: |&str, &[u8]|) -> Result<Repo, String> {
         if dir.exists() {
-            assert!(dir.is_dir(), "creating a subrepo at a nondirectory {}", dir.display());
+            if !dir.is_dir() {
+                return Err(format!("creating a subrepo at a nondirectory {}", dir.display()))
+            }
 
             info!("{} already exists, reusing", dir.display());
         } else {
             // there's away to checkout into an external dir?
-            let ProcessOutput { status, output, error } =
-                Command::new("git")
-                    .arg("clone")
-                    .arg(&self.path)
-                    .arg(&dir)
-                    .output()
-                    .unwrap();
+            let mut command = Command::new("git");
+            command.arg("clone").arg(&self.path).arg(&dir);
+            let status = match stream_output(command, children, job, output) {
+                Ok(status) => status,
+                Err(e) => return Err(format!("Couldn't run git clone: {}", e))
+            };
 
             if !status.success() {
-                fail!("Couldn't copy {} to {}: `{}` `{}`",
-                      self.path.display(),
-                      dir.display(),
-                      str::from_utf8(output.as_slice()),
-                      str::from_utf8(error.as_slice()))
+                return Err(format!("Couldn't copy {} to {}: git clone failed with {}",
+                                   self.path.display(),
+                                   dir.display(),
+                                   status))
             }
         }
-        Repo::new(dir)
+        Ok(Repo::new(dir))
     }
 
     /// Convert a revision to a hash. `None` on failure.
@@ -73,17 +93,81 @@ impl Repo {
 
     /// Retrieve the parent commit of `hash`.
     pub fn parent_commit(&self, hash: &Sha) -> Option<Sha> {
-        self.rev_parse((format!("{}^", hash)).as_slice())
+        self.rev_parse((format!("{}^", hash.value)).as_slice())
+    }
+
+    /// The commits in the revision range `range` (anything `git
+    /// rev-list` understands), newest first. `None` on failure.
+    pub fn rev_list(&self, range: &str) -> Option<Vec<Sha>> {
+        let ProcessOutput { status, output, error } =
+            self.exec("git", ["rev-list".to_string(), range.to_string()]);
+
+        if status.success() {
+            let s = str::from_utf8(output.as_slice()).expect("non-utf8 git output!");
+            Some(s.lines().map(|l| Sha { value: l.trim().to_string() }).collect())
+        } else {
+            warn!("Repo.rev_list failed with {}: {}",
+                  status,
+                  str::from_utf8(error.as_slice()));
+            None
+        }
+    }
+
+    /// `start` and its first-parent ancestors, newest first: the
+    /// commits that following `parent_commit` from `start` visits.
+    pub fn first_parents(&self, start: &Sha) -> Vec<Commit> {
+        let ProcessOutput { status, output, error } =
+            self.exec("git", ["log".to_string(), "--first-parent".to_string(),
+                              "--format=%H %ct %s".to_string(), start.value.clone()]);
+        if !status.success() {
+            warn!("Repo.first_parents failed with {}: {}",
+                  status,
+                  str::from_utf8(error.as_slice()));
+            return Vec::new()
+        }
+
+        let s = str::from_utf8(output.as_slice()).expect("non-utf8 git output!");
+        s.lines().filter_map(|line| {
+            let mut fields = line.splitn(' ', 2);
+            let hash = fields.next();
+            let time = fields.next().and_then(from_str);
+            let subject = fields.next().unwrap_or("");
+            match (hash, time) {
+                (Some(hash), Some(time)) => Some(Commit {
+                    hash: Sha { value: hash.to_string() },
+                    time: time,
+                    subject: subject.to_string(),
+                }),
+                _ => None
+            }
+        }).collect()
     }
 
     /// Checkout the given revision; anything that `git checkout` can
-    /// understand. `false` on failure.
-    #[allow(unused_variable)] // error handling should be better
-    pub fn checkout(&self, rev: &str) -> bool {
+    /// understand. Its output goes to `output`, as for
+    /// `exec_streaming`. `Err` with what went wrong on failure.
+    pub fn checkout(&self, rev: &str, children: &ChildProcesses, job: &Job,
+                    output: |&str, &[u8]|) -> Result<(), String> {
+        let status = match self.exec_streaming("git", ["checkout".to_string(), rev.to_string()],
+                                               children, job, output) {
+            Ok(status) => status,
+            Err(e) => return Err(format!("Couldn't run git checkout: {}", e))
+        };
+        if !status.success() {
+            warn!("Repo.checkout failed with {}", status);
+            return Err(format!("`git checkout {}` failed with {}", rev, status))
+        }
+        Ok(())
+    }
+
+    /// Pull from a remote
+    pub fn pull(&self, remote_branch: &RemoteBranch) -> bool {
         let ProcessOutput { status, output, error } =
-            self.exec("git", ["checkout".to_string(), rev.to_string()]);
+            self.exec("git", ["pull".to_string(),
+                              remote_branch.name.to_string(),
+                              remote_branch.branch.to_string()]);
         if !status.success() {
-            warn!("Repo.checkout failed with {}: {} {}",
+            warn!("Repo.pull failed with {}: {} {}",
                    status,
                    str::from_utf8(output.as_slice()),
                    str::from_utf8(error.as_slice()));
@@ -91,14 +175,14 @@ impl Repo {
         status.success()
     }
 
-    /// Pull from a remote
-    pub fn pull(&self, remote_branch: &RemoteBranch) -> bool {
+    /// Fetch from a remote, without touching the working tree.
+    pub fn fetch(&self, remote_branch: &RemoteBranch) -> bool {
         let ProcessOutput { status, output, error } =
-            self.exec("git", ["pull".to_string(),
+            self.exec("git", ["fetch".to_string(),
                               remote_branch.name.to_string(),
                               remote_branch.branch.to_string()]);
         if !status.success() {
-            warn!("Repo.pull failed with {}: {} {}",
+            warn!("Repo.fetch failed with {}: {} {}",
                    status,
                    str::from_utf8(output.as_slice()),
                    str::from_utf8(error.as_slice()));
@@ -116,6 +200,20 @@ impl Repo {
             .unwrap()
     }
 
+    /// Run `name` like `exec`, but pass each line it writes to
+    /// `output` (along with `out` or `err` for the stream it was
+    /// written to) as soon as it's written, rather than collecting it
+    /// all. It runs in its own process group, tracked in `children`
+    /// as part of `job`, so that it can be killed along with the rest
+    /// of the build.
+    pub fn exec_streaming(&self, name: &str, args: &[String],
+                          children: &ChildProcesses, job: &Job,
+                          output: |&str, &[u8]|) -> IoResult<ProcessExit> {
+        let mut command = Command::new(name);
+        command.args(args).cwd(&self.path);
+        stream_output(command, children, job, output)
+    }
+
     /// Get a UNIX timestamp of the commit date. `None` on failure.
     pub fn ctime(&self, hash: &Sha) -> Option<i64> {
         let time = self.exec("git", &["log".to_string(), hash.value.clone(),
@@ -124,3 +222,67 @@ impl Repo {
         from_str(time.trim())
     }
 }
+
+/// What the tasks reading a command's output send back.
+enum Streamed {
+    Line(&'static str, Vec<u8>),
+    /// The command has exited, and its output has ended or been given
+    /// up on.
+    Exited(IoResult<ProcessExit>),
+}
+
+fn stream_output(mut command: Command, children: &ChildProcesses, job: &Job,
+                 output: |&str, &[u8]|) -> IoResult<ProcessExit> {
+    let mut process = try!(command.detached().spawn());
+    let pid = process.id();
+    children.add(pid, job);
+    drop(process.stdin.take());
+
+    // read both streams in their own tasks, so neither can fill up
+    // and block the process while we wait on the other.
+    let (tx, rx) = channel();
+    let mut ended = vec![];
+    let streams = vec![("out", process.stdout.take().map(|p| box p as Box<Reader + Send>)),
+                       ("err", process.stderr.take().map(|p| box p as Box<Reader + Send>))];
+    for (stream, pipe) in streams.into_iter() {
+        let pipe = match pipe { Some(p) => p, None => continue };
+        let tx = tx.clone();
+        let (end_tx, end_rx) = channel();
+        ended.push(end_rx);
+        task::spawn(proc() {
+            let mut r = BufferedReader::new(pipe);
+            loop {
+                match r.read_until(b'\n') {
+                    Ok(line) => {
+                        if tx.send_opt(Line(stream, line)).is_err() {
+                            break
+                        }
+                    }
+                    Err(_) => break
+                }
+            }
+            // nobody may be waiting any more, if it stayed open too long.
+            let _ = end_tx.send_opt(());
+        });
+    }
+
+    // anything the command leaves running mustn't keep us reading
+    // forever.
+    task::spawn(proc() {
+        let status = process.wait();
+        if !signals::await_output(pid, ended) {
+            warn!("git left its output open, killed what was left");
+        }
+        let _ = tx.send_opt(Exited(status));
+    });
+
+    let mut status = None;
+    for streamed in rx.iter() {
+        match streamed {
+            Line(stream, line) => output(stream, line.as_slice()),
+            Exited(s) => { status = Some(s); break }
+        }
+    }
+    children.remove(pid);
+    status.expect("git's output ended without it exiting")
+}
@@ -0,0 +1,342 @@
+//! The record of finished builds, `already-built.txt`: a line per
+//! build, of the form
+//! `<job>:<status>:<worker>[:<seconds>[:<usage>[:<steps>]]]`, appended
+//! to as builds finish. `<job>` is `<hash>/<variant>` with a build
+//! matrix, and just `<hash>` without. `<usage>` is as given by `Usage::to_field`, and
+//! `<steps>` is a JSON list of `build::Step`s. (Older lines go straight
+//! from `<seconds>` to `<steps>`.)
+//!
+//! Whatever is writing to it holds a `Lock`, so that nothing rewrites
+//! it from under the open file.
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::io::fs;
+use std::io::fs::PathExtensions;
+use std::io::{File, Truncate, Write};
+use std::os;
+
+use libc::{c_int, c_void, pid_t, size_t, ssize_t};
+use libc::consts::os::posix88::{EEXIST, O_CREAT, O_EXCL, O_WRONLY};
+use libc::funcs::posix88::fcntl::open;
+use libc::funcs::posix88::unistd::{close, getpid, write};
+use serialize::json;
+
+use build::{Job, Step};
+use cgroup::Usage;
+use rusage::Resources;
+use signals;
+use git::Sha;
+
+#[deriving(Clone, Show)]
+pub struct Entry {
+    pub hash: Sha,
+    /// The variant of the build matrix built, if there is one.
+    pub variant: Option<String>,
+    /// As given by `build::Outcome::status`.
+    pub status: String,
+    /// `local`, the name of a remote worker, or `control` for things
+    /// cancelled before they were built.
+    pub built_by: String,
+    /// How many seconds the build took, if it was timed.
+    pub duration: Option<i64>,
+    /// What the build used, if it was measured.
+    pub usage: Option<Usage>,
+    /// How each build command went, if known.
+    pub steps: Vec<Step>,
+}
+
+impl Entry {
+    /// Parse a line of the history. `None` for blank lines.
+    pub fn parse(line: &str) -> Option<Entry> {
+        let line = line.trim();
+        if line.is_empty() {
+            return None
+        }
+        // the steps contain colons of their own.
+        let mut fields = line.splitn(':', 4);
+        let job = Job::from_key(fields.next().unwrap());
+        // old histories are just a list of hashes.
+        let status = fields.next().unwrap_or("unknown");
+        let built_by = fields.next().unwrap_or("unknown");
+        let duration = fields.next().and_then(from_str);
+        let (usage, steps) = match fields.next() {
+            None => (None, None),
+            Some(rest) if rest.starts_with("[") => (None, Some(rest)),
+            Some(rest) => {
+                let mut rest = rest.splitn(':', 1);
+                (rest.next().and_then(Usage::from_field), rest.next())
+            }
+        };
+        let steps = steps.and_then(|s| json::decode(s).ok()).unwrap_or(Vec::new());
+        Some(Entry {
+            hash: job.hash,
+            variant: job.variant,
+            status: status.to_string(),
+            built_by: built_by.to_string(),
+            duration: duration,
+            usage: usage,
+            steps: steps,
+        })
+    }
+
+    /// The line of the history recording this.
+    pub fn to_line(&self) -> String {
+        let mut line = format!("{}:{}:{}", self.job().key(), self.status, self.built_by);
+        if self.duration.is_some() || self.usage.is_some() || !self.steps.is_empty() {
+            line.push_str(format!(":{}", self.duration.map(|d| d.to_string())
+                                                        .unwrap_or(String::new())).as_slice());
+        }
+        if self.usage.is_some() || !self.steps.is_empty() {
+            line.push_str(format!(":{}", self.usage.as_ref().map(|u| u.to_field())
+                                                         .unwrap_or(String::new())).as_slice());
+        }
+        if !self.steps.is_empty() {
+            line.push_str(format!(":{}", json::encode(&self.steps)).as_slice());
+        }
+        line
+    }
+
+    pub fn job(&self) -> Job {
+        Job::new(self.hash.clone(), self.variant.clone())
+    }
+
+    /// Whether the build doesn't need to be done again: not if it
+    /// was interrupted, or couldn't be done for reasons that
+    /// have nothing to do with the commit itself.
+    pub fn is_done(&self) -> bool {
+        match self.status.as_slice() {
+            "interrupted" | "infrastructure" => false,
+            _ => true
+        }
+    }
+
+    pub fn is_failure(&self) -> bool {
+        self.status.as_slice() == "failure"
+    }
+
+    /// What the build commands used altogether: the total CPU time,
+    /// and the largest resident set size of any of them. `None` if
+    /// none were measured.
+    pub fn resources(&self) -> Option<Resources> {
+        let measured: Vec<&Resources> = self.steps.iter()
+            .filter_map(|s| s.resources.as_ref())
+            .collect();
+        if measured.is_empty() {
+            return None
+        }
+        Some(Resources {
+            user_ms: measured.iter().fold(0, |a, r| a + r.user_ms),
+            sys_ms: measured.iter().fold(0, |a, r| a + r.sys_ms),
+            max_rss_kb: measured.iter().fold(0, |a, r| cmp::max(a, r.max_rss_kb)),
+        })
+    }
+}
+
+/// Every entry in the history at `path`, oldest first.
+pub fn load(path: &Path) -> Vec<Entry> {
+    let text = File::open(path).read_to_string()
+        .ok().expect(format!("couldn't read {}", path.display()).as_slice());
+    text.as_slice().lines().filter_map(Entry::parse).collect()
+}
+
+/// The builds in the history at `path` that don't need doing again.
+pub fn done(path: &Path) -> HashSet<Job> {
+    latest(load(path).as_slice()).into_iter()
+        .filter(|e| e.is_done())
+        .map(|e| e.job())
+        .collect()
+}
+
+/// The average number of seconds that the timed, completed builds in
+/// `entries` took. `None` if there aren't any.
+pub fn mean_duration(entries: &[Entry]) -> Option<f64> {
+    let timed: Vec<i64> = entries.iter()
+        .filter(|e| e.status.as_slice() == "success" || e.is_failure())
+        .filter_map(|e| e.duration)
+        .collect();
+    if timed.is_empty() {
+        None
+    } else {
+        Some(timed.iter().fold(0, |a, &b| a + b) as f64 / timed.len() as f64)
+    }
+}
+
+/// The most recent entry for each build (each commit, in each
+/// variant) in `entries`, in the order those entries were made.
+pub fn latest(entries: &[Entry]) -> Vec<Entry> {
+    let mut last = HashMap::new();
+    for (i, e) in entries.iter().enumerate() {
+        last.insert(e.job(), i);
+    }
+    entries.iter().enumerate()
+        .filter(|&(i, e)| last.find(&e.job()) == Some(&i))
+        .map(|(_, e)| e.clone())
+        .collect()
+}
+
+/// A claim on the history at some path, by the one process allowed to
+/// write to it: `<path>.lock`, holding that process's pid. It's
+/// released when dropped.
+pub struct Lock {
+    path: Path,
+}
+
+impl Lock {
+    /// Claim the history at `history`. `Err` with the pid of the
+    /// process that already has it.
+    pub fn take(history: &Path) -> Result<Lock, pid_t> {
+        let path = lock_path(history);
+        loop {
+            // creating it is what claims it, so two can't both succeed.
+            let fd = path.with_c_str(|p| unsafe { open(p, O_CREAT | O_EXCL | O_WRONLY, 0o644) });
+            if fd >= 0 {
+                let pid = format!("{}", unsafe { getpid() });
+                let written = unsafe {
+                    write(fd, pid.as_ptr() as *const c_void, pid.len() as size_t)
+                };
+                unsafe { close(fd); }
+                if written != pid.len() as ssize_t {
+                    fail!("couldn't write {}: {}", path.display(), os::last_os_error());
+                }
+                return Ok(Lock { path: path })
+            }
+            if os::errno() as c_int != EEXIST {
+                fail!("couldn't create {}: {}", path.display(), os::last_os_error());
+            }
+            match holder(history) {
+                Some(pid) => return Err(pid),
+                // left behind by a process that has died.
+                None => { let _ = fs::unlink(&path); }
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::unlink(&self.path);
+    }
+}
+
+fn lock_path(history: &Path) -> Path {
+    history.with_extension("lock")
+}
+
+/// The pid of the process holding the history at `history`, if there
+/// is one. A lock left behind by a process that has since died
+/// doesn't count.
+pub fn holder(history: &Path) -> Option<pid_t> {
+    let path = lock_path(history);
+    if !path.exists() {
+        return None
+    }
+    let pid: pid_t = match File::open(&path).read_to_string().ok()
+                         .and_then(|s| from_str(s.as_slice().trim())) {
+        Some(pid) => pid,
+        None => return None
+    };
+    if signals::is_alive(pid) { Some(pid) } else { None }
+}
+
+/// Remove every entry for `hash`, in any variant, from the history at `path`. `false`
+/// if there weren't any.
+pub fn forget(path: &Path, hash: &Sha) -> bool {
+    let entries = load(path);
+    let kept: Vec<&Entry> = entries.iter().filter(|e| e.hash != *hash).collect();
+    if kept.len() == entries.len() {
+        return false
+    }
+
+    // write it out alongside and move it over the old one, so a crash
+    // can't leave half a history.
+    let tmp = path.with_extension("tmp");
+    {
+        let mut f = File::open_mode(&tmp, Truncate, Write)
+            .ok().expect(format!("couldn't write {}", tmp.display()).as_slice());
+        for e in kept.iter() {
+            (writeln!(&mut f, "{}", e.to_line())).unwrap();
+        }
+    }
+    fs::rename(&tmp, path).unwrap();
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{File, TempDir};
+
+    use libc::funcs::posix88::unistd::getpid;
+    use super::{Entry, Lock, holder};
+
+    #[test]
+    fn lock_is_exclusive_until_dropped() {
+        let dir = TempDir::new("multibuilder-history").unwrap();
+        let history = dir.path().join("already-built.txt");
+        let me = unsafe { getpid() };
+
+        assert_eq!(holder(&history), None);
+        let lock = Lock::take(&history).ok().expect("nobody had the lock");
+        assert_eq!(holder(&history), Some(me));
+        assert_eq!(Lock::take(&history).err(), Some(me));
+
+        drop(lock);
+        assert_eq!(holder(&history), None);
+        assert!(Lock::take(&history).is_ok());
+    }
+
+    #[test]
+    fn stale_lock_is_taken_over() {
+        let dir = TempDir::new("multibuilder-history").unwrap();
+        let history = dir.path().join("already-built.txt");
+        // beyond any pid_max, so nobody's.
+        File::create(&history.with_extension("lock")).write_str("999999999").unwrap();
+
+        assert_eq!(holder(&history), None);
+        let _lock = Lock::take(&history).ok().expect("the lock was stale");
+        assert_eq!(holder(&history), Some(unsafe { getpid() }));
+    }
+
+    #[test]
+    fn entry_round_trip() {
+        let lines = ["0123abcd:success:local",
+                     "0123abcd/debug:failure:builder-1:42",
+                     "0123abcd:success:local:42:3145728/1500",
+                     "0123abcd:timed-out:local::/20"];
+        for line in lines.iter() {
+            let entry = Entry::parse(*line).unwrap();
+            assert_eq!(entry.to_line(), line.to_string());
+        }
+    }
+
+    #[test]
+    fn entry_fields() {
+        let e = Entry::parse("0123abcd/debug:failure:builder-1:42:3145728/1500").unwrap();
+        assert_eq!(e.hash.value, "0123abcd".to_string());
+        assert_eq!(e.variant, Some("debug".to_string()));
+        assert_eq!(e.status, "failure".to_string());
+        assert_eq!(e.built_by, "builder-1".to_string());
+        assert_eq!(e.duration, Some(42));
+        let usage = e.usage.unwrap();
+        assert_eq!((usage.peak_memory, usage.cpu_ms), (Some(3145728), Some(1500)));
+        assert!(e.steps.is_empty());
+    }
+
+    #[test]
+    fn old_entries() {
+        // just a hash.
+        let e = Entry::parse("0123abcd\n").unwrap();
+        assert_eq!(e.hash.value, "0123abcd".to_string());
+        assert_eq!(e.variant, None);
+        assert_eq!(e.status, "unknown".to_string());
+        assert!(e.is_done());
+
+        // steps straight after the duration.
+        let e = Entry::parse("0123abcd:interrupted:local:7:[]").unwrap();
+        assert_eq!(e.duration, Some(7));
+        assert!(e.usage.is_none());
+        assert!(!e.is_done());
+
+        assert!(Entry::parse("  ").is_none());
+    }
+}
@@ -5,27 +5,41 @@
 extern crate serialize;
 extern crate getopts;
 extern crate glob;
+extern crate libc;
 extern crate term;
+extern crate time;
 #[phase(plugin, link)]
 extern crate log;
 
-use std::io::fs::PathExtensions;
+use std::collections::{HashMap, TreeMap};
 use std::io::Command as IoCommand;
-use std::io::{Append, ReadWrite, stdout, File, timer};
-use std::str;
+use std::io::fs::PathExtensions;
+use std::io::{Append, ReadWrite, stdout, File};
 use std::vec::Vec;
 
-use std::collections::HashSet;
 use serialize::Decodable;
 use serialize::json;
 use std::sync::Arc;
 
 use commit_walker::CommitWalker;
-use git::{Repo, Sha};
+use coordinator::Coordinator;
+use git::Repo;
+use output::OutputMovement;
 
+pub mod auth;
 pub mod commit_walker;
+pub mod control;
+pub mod coordinator;
 pub mod git;
+pub mod history;
 pub mod build;
+pub mod build_log;
+pub mod cgroup;
+pub mod cli;
+pub mod output;
+pub mod remote;
+pub mod rusage;
+pub mod signals;
 pub mod task_worker;
 
 fn is_dir(p: Path) -> Path {
@@ -49,37 +63,285 @@ struct Config {
     main_repo: String,
     /// the commands to run when building.
     build_commands: Vec<Command>,
+    /// the variants to build each commit in, e.g. debug and release.
+    /// `None` to build each commit once, with `build_commands`.
+    variants: Option<Vec<Variant>>,
     /// the branch to pull from when updating the repo
     pull_from: Option<git::RemoteBranch>,
     /// a unix timestamp. if a commit is older than this, it won't be built.
     earliest_build: Option<i64>,
     when_finished: Vec<Command>,
+    /// where to listen for remote workers. `None` to only build
+    /// locally.
+    listen: Option<remote::Address>,
+    /// how many seconds a remote worker can go without being heard
+    /// from before its build is given to someone else (default 60).
+    lease_seconds: Option<i64>,
+    /// a file containing the secret from which the keys remote workers
+    /// authenticate with are derived. `None` to accept anyone.
+    secret_file: Option<String>,
+    /// how many seconds between checks of `pull_from` for new commits
+    /// (default 60).
+    poll_seconds: Option<i64>,
+    /// how many seconds builds get to exit after `SIGTERM` before being
+    /// killed, when multibuilder is interrupted twice (default 10).
+    shutdown_grace_seconds: Option<i64>,
+    /// where to create a unix socket accepting control commands. `None`
+    /// for no control socket.
+    control_socket: Option<String>,
+    /// how long builds may run before they are killed. `None` for no
+    /// limits.
+    timeouts: Option<build::Timeouts>,
+    /// where to write the logs of builds, and how much of them to
+    /// keep. `None` for the defaults.
+    logs: Option<build_log::LogSettings>,
+    /// what build commands' environment is made of. `None` to inherit
+    /// ours.
+    environment: Option<build::Environment>,
+    /// the resources each build command may use, and its priority.
+    /// `None` for no limits.
+    limits: Option<build::Limits>,
+    /// how to isolate builds from the rest of the machine. `None` for
+    /// no isolation.
+    sandbox: Option<build::Sandbox>,
+    /// where to put each build in its own cgroup, to measure and limit
+    /// what it uses. `None` to not use cgroups.
+    cgroups: Option<cgroup::CgroupSettings>,
 }
 
-#[deriving(Encodable, Decodable, Show)]
-struct OutputMovement {
-    /// The directory to place the <hash> directory which the
-    /// `to_move` files get placed in.
-    parent_dir: String,
-    /// The files/directories to move from the build dir to
-    /// `parent_dir/<hash>/`.
-    to_move: Vec<String>
-}
+static DEFAULT_LEASE_SECONDS: i64 = 60;
+static DEFAULT_POLL_SECONDS: i64 = 60;
+
+static USAGE: &'static str = "Usage: {} [options] [COMMAND]
+
+Commands:
+    run                  build every unbuilt ancestor of HEAD (the default)
+    build REV...         build the given commits, even if they've been built
+    retry-failures       build every commit whose last build failed again
+    plan                 list what `run` would build, and estimate how long it'd take
+    status               summarise the history, and what's being built
+    history [RANGE]      list the last result of each commit (in RANGE)
+    forget REV           remove REV from the history
+    show REV             print the results and artifacts of REV
+    tail REV [VARIANT]   print the log of REV, following it while it's being built
+    worker               build for a remote coordinator (see `worker --help`)
+    worker-key NAME      print the key for the remote worker NAME";
 
 #[deriving(Encodable, Decodable, Clone, Show)]
 pub struct Command {
+    /// the program to run, with `args`. Exactly one of `name`, `shell`
+    /// and `script` must be given.
+    name: Option<String>,
+    args: Option<Vec<String>>,
+    /// a command line to run with `/bin/sh -c`.
+    shell: Option<String>,
+    /// lines of a shell script, run with `/bin/sh -e`, so that it
+    /// stops at the first one that fails.
+    script: Option<Vec<String>>,
+    /// how many seconds this command may run for, overriding
+    /// `timeouts.command`.
+    timeout_seconds: Option<i64>,
+    /// environment variables to set for this command, on top of the
+    /// inherited ones.
+    env: Option<HashMap<String, String>>,
+    /// the directory to run this command in, relative to the checkout
+    /// (or, for `when_finished`, the working directory).
+    cwd: Option<String>,
+}
+
+impl Command {
+    /// Check that exactly one way of saying what to run is used.
+    fn check(&self) -> Result<(), String> {
+        let given = [self.name.is_some(), self.shell.is_some(), self.script.is_some()];
+        if given.iter().filter(|&&b| b).count() != 1 {
+            return Err("exactly one of `name`, `shell` and `script` must be given".to_string())
+        }
+        if self.args.is_some() && self.name.is_none() {
+            return Err("`args` can only be given with `name`".to_string())
+        }
+        Ok(())
+    }
+
+    /// The program to run, and its arguments.
+    fn program(&self) -> (String, Vec<String>) {
+        let sh = "/bin/sh".to_string();
+        match (&self.name, &self.shell, &self.script) {
+            (&Some(ref name), _, _) => (name.clone(), self.args.clone().unwrap_or(Vec::new())),
+            (_, &Some(ref line), _) => (sh, vec!["-c".to_string(), line.clone()]),
+            (_, _, &Some(ref lines)) => {
+                (sh, vec!["-e".to_string(), "-c".to_string(), lines.connect("\n")])
+            }
+            _ => fail!("a command with nothing to run: {}", self)
+        }
+    }
+
+    /// This, as it would be typed.
+    pub fn line(&self) -> String {
+        match (&self.shell, &self.script) {
+            (&Some(ref line), _) => line.clone(),
+            (_, &Some(ref lines)) => lines.connect("; "),
+            _ => {
+                let (name, args) = self.program();
+                let mut words = vec![name];
+                words.push_all(args.as_slice());
+                words.connect(" ")
+            }
+        }
+    }
+
+    /// `base`, with this command's own variables set too. `expand` is
+    /// applied to their values.
+    pub fn env(&self, base: &TreeMap<String, String>,
+               expand: |&str| -> String) -> TreeMap<String, String> {
+        let mut env = base.clone();
+        match self.env {
+            Some(ref vars) => {
+                for (name, value) in vars.iter() {
+                    env.insert(name.clone(), expand(value.as_slice()));
+                }
+            }
+            None => {}
+        }
+        env
+    }
+
+    /// The process to run this as, in `dir` (or `cwd` inside it), with
+    /// exactly the environment `env`, and run via the command words in
+    /// `prefix` (such as `nice -n 10`), if any. `expand` is applied to
+    /// what's run and `cwd`, except for a `shell` or `script` line,
+    /// which gets `expand_shell`.
+    pub fn process(&self, dir: &Path, env: &TreeMap<String, String>, prefix: &[String],
+                   expand: |&str| -> String, expand_shell: |&str| -> String) -> IoCommand {
+        let (name, mut args) = self.program();
+        // `sh -c` takes the line last.
+        let line = if self.name.is_none() { args.pop() } else { None };
+        let mut words: Vec<String> = prefix.iter().map(|w| w.clone()).collect();
+        words.push(expand(name.as_slice()));
+        words.extend(args.iter().map(|a| expand(a.as_slice())));
+        words.extend(line.iter().map(|l| expand_shell(l.as_slice())));
+
+        let mut process = IoCommand::new(words[0].as_slice());
+        process.args(words.slice_from(1));
+        match self.cwd {
+            Some(ref cwd) => process.cwd(&dir.join(expand(cwd.as_slice()))),
+            None => process.cwd(dir),
+        };
+        let env: Vec<(String, String)> = env.iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        process.env_set_all(env.as_slice());
+        process
+    }
+}
+
+/// One configuration of the build matrix.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct Variant {
+    /// what the builds of this variant are recorded as: `<hash>/<name>`.
     name: String,
-    args: Vec<String>
+    /// the commands to build this variant with, instead of
+    /// `build_commands`.
+    build_commands: Option<Vec<Command>>,
+    /// environment variables to set for each of this variant's build
+    /// commands.
+    env: Option<HashMap<String, String>>,
+}
+
+impl Variant {
+    /// The variant called `name` in `variants`.
+    pub fn find<'a>(variants: &'a [Variant], name: &str) -> &'a Variant {
+        match variants.iter().find(|v| v.name.as_slice() == name) {
+            Some(v) => v,
+            None => fail!("unknown variant `{}`", name)
+        }
+    }
+
+    /// Whether `variants` has one called `name`.
+    pub fn exists(variants: &[Variant], name: &str) -> bool {
+        variants.iter().any(|v| v.name.as_slice() == name)
+    }
+
+    /// The commands to build this variant with.
+    pub fn commands<'a>(&'a self, default: &'a [Command]) -> &'a [Command] {
+        match self.build_commands {
+            Some(ref commands) => commands.as_slice(),
+            None => default
+        }
+    }
+
+    /// Check that the name can be used in paths and the history.
+    fn check(&self) -> Result<(), String> {
+        let ok = |c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '.';
+        if self.name.is_empty() || self.name.as_slice().starts_with(".") ||
+                !self.name.as_slice().chars().all(ok) {
+            return Err("names can only contain letters, digits, `_`, `-` and `.`".to_string())
+        }
+        Ok(())
+    }
 }
 
 impl Config {
+    /// The variants each commit is built in: their names, or just
+    /// `None` without a build matrix.
+    fn variant_names(&self) -> Vec<Option<String>> {
+        match self.variants {
+            Some(ref variants) => variants.iter().map(|v| Some(v.name.clone())).collect(),
+            None => vec![None]
+        }
+    }
+
+    /// How builds are to be done, with the defaults filled in.
+    fn build_settings(&self) -> build::BuildSettings {
+        build::BuildSettings {
+            timeouts: self.timeouts.clone().unwrap_or(build::Timeouts::none()),
+            logs: self.logs.clone().unwrap_or(build_log::LogSettings::default()),
+            environment: self.environment.clone().unwrap_or(build::Environment::inherit()),
+            limits: self.limits.clone().unwrap_or(build::Limits::none()),
+            sandbox: self.sandbox.clone(),
+            cgroups: self.cgroups.clone(),
+        }
+    }
+
     fn load(p: &Path) -> Config {
         match File::open(p) {
             Err(e) => fail!("couldn't open {} ({})", p.display(), e),
             Ok(ref mut reader) => {
                 let msg = format!("{} is invalid json", p.display());
                 let json = json::from_reader(reader as &mut Reader).ok().expect(msg.as_slice());
-                Decodable::decode(&mut json::Decoder::new(json)).unwrap()
+                let config: Config = Decodable::decode(&mut json::Decoder::new(json)).unwrap();
+                for cmd in config.build_commands.iter().chain(config.when_finished.iter()) {
+                    match cmd.check() {
+                        Ok(()) => {}
+                        Err(e) => fail!("invalid command {} in {}: {}", cmd, p.display(), e)
+                    }
+                }
+                match config.poll_seconds {
+                    // 0 would fetch as fast as git can go, forever.
+                    Some(secs) if secs < 1 => {
+                        fail!("`poll_seconds` in {} must be at least 1, not {}", p.display(), secs)
+                    }
+                    _ => {}
+                }
+                if config.variants.as_ref().map_or(false, |v| v.is_empty()) {
+                    fail!("`variants` in {} is empty: nothing would be built", p.display())
+                }
+                let variants = config.variants.clone().unwrap_or(Vec::new());
+                for (i, v) in variants.iter().enumerate() {
+                    match v.check() {
+                        Ok(()) => {}
+                        Err(e) => fail!("invalid variant `{}` in {}: {}", v.name, p.display(), e)
+                    }
+                    if variants.slice_to(i).iter().any(|w| w.name == v.name) {
+                        fail!("variant `{}` is defined twice in {}", v.name, p.display())
+                    }
+                    for cmd in v.build_commands.iter().flat_map(|c| c.iter()) {
+                        match cmd.check() {
+                            Ok(()) => {}
+                            Err(e) => fail!("invalid command {} in {}: {}", cmd, p.display(), e)
+                        }
+                    }
+                }
+                config
             },
         }
     }
@@ -88,17 +350,31 @@ impl Config {
 fn main() {
     let args = std::os::args();
 
+    if args.len() > 1 && args[1].as_slice() == "worker" {
+        remote::worker_main(args[0].as_slice(), args.slice_from(2));
+        return;
+    }
+    if args.len() > 1 && args[1].as_slice() == "worker-key" {
+        remote::worker_key_main(args[0].as_slice(), args.slice_from(2));
+        return;
+    }
+
     let opts =
         vec![getopts::optopt("c", "config", "configuration file (default ./config.json)", "PATH"),
           getopts::optopt("a", "already-built",
                          "file of hashes already built (default ./already-built.txt)", "PATH"),
+          getopts::optflag("d", "daemon",
+                           "keep running after building everything, waiting for new commits"),
+          getopts::optflag("", "failed", "only show failures (for `history`)"),
           getopts::optflag("h", "help", "show this help message")];
 
-    let (config_path, already_built_path) = match getopts::getopts(args.tail(), opts.as_slice()) {
+    let (config_path, already_built_path, daemon, failed_only, free) =
+            match getopts::getopts(args.tail(), opts.as_slice()) {
         Err(err) => fail!("{}", err),
         Ok(matches) => {
             if matches.opt_present("h") || matches.opt_present("help") {
-                println!("{}", getopts::usage(args[0].as_slice(), opts.as_slice()));
+                println!("{}", getopts::usage(USAGE.replace("{}", args[0].as_slice()).as_slice(),
+                                              opts.as_slice()));
                 return;
             }
 
@@ -117,28 +393,62 @@ fn main() {
                 Path::new("already-built.txt")
             };
 
-            (cfg, built)
+            (cfg, built, matches.opt_present("d"), matches.opt_present("failed"), matches.free.clone())
         }
     };
 
     let config = Config::load(&config_path);
 
-    let mut already_built = HashSet::new();
+    let subcommand = free.as_slice().head().map(|s| s.as_slice()).unwrap_or("run");
+    let sub_args = if free.is_empty() { free.as_slice() } else { free.tail() };
+    match (subcommand, sub_args.len()) {
+        ("run", 0) => run(&config, &already_built_path, daemon, None),
+        ("build", n) if n > 0 => {
+            if !cli::enqueue_running(&config, sub_args) {
+                run(&config, &already_built_path, false, Some(sub_args.to_vec()));
+            }
+        }
+        ("retry-failures", 0) => {
+            let failed = cli::failures(&already_built_path);
+            if failed.is_empty() {
+                println!("No failures to retry.");
+            } else if !cli::enqueue_running(&config, failed.as_slice()) {
+                run(&config, &already_built_path, false, Some(failed));
+            }
+        }
+        ("status", 0) => cli::status(&config, &already_built_path),
+        ("plan", 0) => cli::plan(&config, &already_built_path),
+        ("history", 0) => cli::history(&config, &already_built_path, failed_only, None),
+        ("history", 1) => cli::history(&config, &already_built_path, failed_only,
+                                       Some(sub_args[0].as_slice())),
+        ("forget", 1) => cli::forget(&config, &already_built_path, sub_args[0].as_slice()),
+        ("show", 1) => cli::show(&config, &already_built_path, sub_args[0].as_slice()),
+        ("tail", 1) => cli::tail(&config, sub_args[0].as_slice(), None),
+        ("tail", 2) => cli::tail(&config, sub_args[0].as_slice(), Some(sub_args[1].as_slice())),
+        _ => fail!("invalid arguments `{}`, see --help", free.connect(" "))
+    }
+}
+
+/// Build things: the commits in `only`, if given, and otherwise every
+/// unbuilt ancestor of `HEAD`.
+fn run(config: &Config, already_built_path: &Path, daemon: bool, only: Option<Vec<String>>) {
+    // held until we exit, so that `forget` can't replace the file
+    // we're appending to.
+    let _lock = match history::Lock::take(already_built_path) {
+        Ok(lock) => lock,
+        Err(pid) => fail!("{} is in use by another multibuilder (pid {})",
+                          already_built_path.display(), pid)
+    };
 
     // FIXME: allow this to be created automagically.
-    let already_built_file = File::open_mode(&already_built_path, Append, ReadWrite);
+    let already_built_file = File::open_mode(already_built_path, Append, ReadWrite);
 
-    let mut already_built_file =
+    let already_built_file =
         already_built_file.ok().expect(format!("Error opening {}", already_built_path.display()).as_slice());
 
-    let text = String::from_utf8(already_built_file.read_to_end().unwrap())
-        .ok().expect("already-built non-utf8!");
+    let already_built = history::done(already_built_path);
 
-    for hash in text.as_slice().split('\n') {
-        already_built.insert(git::Sha { value: hash.split(':').next().unwrap().to_string() });
-    }
-
-    println!("Found {} already built commits", already_built.len());
+    println!("Found {} finished builds", already_built.len());
 
     let num_workers = config.num_local_builders.unwrap_or_default();
     println!("Running with max {} workers", num_workers);
@@ -157,156 +467,117 @@ fn main() {
         }
     }
 
-    let build_commands = Arc::new(config.build_commands.clone());
-
     let mut walker = CommitWalker::new(&*main_repo,
                                        already_built,
                                        already_built_file,
                                        config.pull_from.as_ref(),
-                                       config.earliest_build);
-
-    // start the workers a-working. This vec contains a worker iff
-    // it's currently working (or just finished a job); they get
-    // removed when we've finished (e.g. run out of commits).
-    let mut workers = Vec::with_capacity(num_workers);
-    for i in range(0, num_workers) {
-        match walker.find_unbuilt_commit() {
-            None => { info!("No more commits to build"); break },
-            Some(hash) => {
-                let worker = task_worker::TaskWorker::new(build_dir.clone(),
-                                                          main_repo.clone(),
-                                                          build_commands.clone());
-
-                info!("Sending {} to worker {}", hash.value, i);
-                worker.send(build::BuildHash(hash));
-                workers.push(worker);
-            }
-        }
-    }
-    'outer: loop {
-        if workers.is_empty() {
-            info!("No more builds, running when_finished");
-            for cmd in config.when_finished.iter() {
-                debug!("Running {}", cmd);
-                let mut result = IoCommand::new(cmd.name.as_slice());
-                result.args(cmd.args.as_slice());
-                let result = result.output().unwrap();
-
-                if !result.status.success() {
-                    error!("{} failed", cmd.name);
+                                       config.earliest_build,
+                                       config.variant_names());
+
+    match only {
+        None => {}
+        Some(ref revs) => {
+            walker.stop_walking();
+            for rev in revs.iter() {
+                match main_repo.rev_parse(rev.as_slice()) {
+                    Some(hash) => walker.enqueue(hash),
+                    None => fail!("unknown revision `{}`", rev)
                 }
             }
-            return;
         }
+    }
 
-        // we need to remove items mid-iteration.
-        // FIXME using select + a timeout would be nicer here?
-        let mut found_a_message = false;
-        let mut term = term::stdout().unwrap();
-        'scanner: for i in range(0, workers.len()) {
-            match workers[i].recv_opt() {
-                // stream closed.
-                Err(()) => {
-                    debug!("removing a worker, other end hung up");
-                    workers.swap_remove(i);
+    // everything the main loop needs to react to comes down here.
+    let (events_tx, events) = std::comm::channel();
+
+    let listening = match config.listen {
+        None => false,
+        Some(ref addr) => {
+            let output = config.output.clone().map(|o| Arc::new(o));
+            let lease_secs = config.lease_seconds.unwrap_or(DEFAULT_LEASE_SECONDS);
+            let setup = remote::WorkerSetup {
+                build_commands: config.build_commands.clone(),
+                variants: config.variants.clone().unwrap_or(Vec::new()),
+                to_move: output.as_ref().map(|o| o.to_move.clone()),
+                heartbeat_secs: std::cmp::max(1, lease_secs / 4),
+                settings: {
+                    let mut settings = config.build_settings();
+                    // the directory is up to the worker.
+                    settings.logs.dir = None;
+                    settings
                 },
-                // it was the crushing disappointment of failure. :(
-                Ok(build::Failure(hash)) => {
-                    found_a_message = true;
-                    term.fg(term::color::RED).unwrap();
-                    println!("{} failed.", hash.value);
-                    term.reset().unwrap();
-
-                    walker.register_built(hash.clone(), false);
-                }
-                // \o/ we won!
-                Ok(build::Success(loc, hash)) => {
-                    found_a_message = true;
-                    term.fg(term::color::GREEN).unwrap();
-                    println!("{} succeeded.", hash.value);
-                    term.reset().unwrap();
-
-                    // FIXME: break this out.
-                    match config.output {
-                        None => {}
-                        Some(ref output) => {
-                            let suboutput_dir = Path::new(output.parent_dir.as_slice())
-                                .join(hash.value.as_slice());
-
-                            // create the final output directory.
-                            let mkdir = IoCommand::new("mkdir")
-                                .arg("-p")
-                                .arg(&suboutput_dir)
-                                .output().unwrap();
-
-                            if !mkdir.status.success() {
-                                fail!("mkdir failed on {} with {}",
-                                      suboutput_dir.display(),
-                                      std::str::from_utf8(mkdir.error.as_slice()));
-                            }
-
-                            match loc {
-                                build::Local(p) => {
-                                    // move some subdirectory of the final
-                                    // output (in `p`) to the appropriate
-                                    // place.
-                                    let mut move_args: Vec<String> = output.to_move.iter().map(|s| {
-                                        let glob_path = p.join(s.as_slice());
-                                        let glob_str = format!("{}", glob_path.display());
-                                        let glob = glob::glob(glob_str.as_slice());
-
-                                        // XXX shouldn't be using strings here :(
-                                        glob.map(|x| format!("{}", x.display())).collect::<Vec<String>>()
-                                    }).collect::<Vec<Vec<String>>>().as_slice().concat_vec();
-                                    move_args.push("-vt".to_string());
-                                    // XXX strings
-                                    move_args.push(format!("{}", suboutput_dir.display()));
-
-                                    // move what we want.
-                                    let mv = IoCommand::new("mv").args(move_args.as_slice()).output().unwrap();
-                                    if !mv.status.success() {
-                                        println!("mv: {}", str::from_utf8(mv.output.as_slice()));
-                                        fail!("mv failed with {}", str::from_utf8(mv.error.as_slice()))
-                                    }
-
-                                    // delete the build dir.
-                                    let rm = IoCommand::new("rm")
-                                        .arg("-rf")
-                                        .arg(&p)
-                                        .output().unwrap();
-
-                                    if !rm.status.success() {
-                                        println!("rm: {}", str::from_utf8(rm.output.as_slice()));
-                                        fail!("rm failed on {} with {}", p.display(),
-                                              std::str::from_utf8(rm.error.as_slice()));
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                    walker.register_built(hash.clone(), true);
-                }
-            }
-            // get back to work!
-            match walker.find_unbuilt_commit() {
-                None => {
-                    // no more commits so remove this (now useless) worker.
-                    debug!("Removing worker, it's useless now");
-                    workers.swap_remove(i);
-                    break 'scanner;
-                }
-                Some(hash) => workers[i].send(build::BuildHash(hash)),
+            };
+            let secret = config.secret_file.as_ref().map(|p| auth::load_secret(&Path::new(p.as_slice())));
+            if secret.is_none() {
+                warn!("no secret_file, so accepting remote workers without authentication");
             }
+            remote::listen(addr.clone(), setup, lease_secs, secret, output, events_tx.clone());
+            true
         }
+    };
 
-        // FIXME: handle fetching new commits
-
-        // only pause if we didn't do anything in the last run.
-        if !found_a_message {
-            // no need to busy wait
-            let mut timer = timer::Timer::new().ok().expect("No timer??");
-            timer.sleep(std::time::Duration::seconds(500));
+    match config.pull_from {
+        // pulling would start walking from the new HEAD.
+        Some(_) if only.is_some() => {}
+        Some(ref remote) => {
+            let interval = config.poll_seconds.unwrap_or(DEFAULT_POLL_SECONDS);
+            coordinator::watch_remote((*main_repo).clone(), remote.clone(), interval,
+                                      events_tx.clone());
         }
+        None if daemon => warn!("running as a daemon without `pull_from`, nothing new will arrive"),
+        None => {}
+    }
+
+    match config.control_socket {
+        Some(ref path) => control::listen(Path::new(path.as_slice()), events_tx.clone()),
+        None => {}
+    }
+
+    signals::watch(&[signals::Interrupt, signals::Terminate, signals::HangUp,
+                     signals::User1, signals::User2],
+                   events_tx.clone(), coordinator::Signalled);
+
+    let mut coordinator = Coordinator::new(config, walker, events_tx, events,
+                                           build_dir, main_repo.clone(), listening, daemon);
+    coordinator.run();
+}
+
+#[cfg(test)]
+mod test {
+    use serialize::json;
+
+    use super::Command;
+
+    fn command(s: &str) -> Command {
+        json::decode(s).ok().expect(s)
+    }
+
+    #[test]
+    fn exactly_one_way_to_run() {
+        assert!(command(r#"{"name": "make", "args": ["-j4"]}"#).check().is_ok());
+        assert!(command(r#"{"shell": "make | tee log"}"#).check().is_ok());
+        assert!(command(r#"{"script": ["cd docs", "make"]}"#).check().is_ok());
+
+        assert!(command(r#"{}"#).check().is_err());
+        assert!(command(r#"{"name": "make", "shell": "make"}"#).check().is_err());
+        assert!(command(r#"{"shell": "make", "args": ["-j4"]}"#).check().is_err());
+    }
+
+    #[test]
+    fn programs_and_lines() {
+        let c = command(r#"{"name": "make", "args": ["-j4", "all"]}"#);
+        assert_eq!(c.program(), ("make".to_string(), vec!["-j4".to_string(), "all".to_string()]));
+        assert_eq!(c.line(), "make -j4 all".to_string());
+
+        let c = command(r#"{"shell": "make | tee log"}"#);
+        assert_eq!(c.program(), ("/bin/sh".to_string(),
+                                 vec!["-c".to_string(), "make | tee log".to_string()]));
+        assert_eq!(c.line(), "make | tee log".to_string());
+
+        let c = command(r#"{"script": ["cd docs", "make"]}"#);
+        assert_eq!(c.program(), ("/bin/sh".to_string(),
+                                 vec!["-e".to_string(), "-c".to_string(),
+                                      "cd docs\nmake".to_string()]));
+        assert_eq!(c.line(), "cd docs; make".to_string());
     }
 }
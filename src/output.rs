@@ -0,0 +1,153 @@
+//! Moving the interesting parts of a build into the output directory,
+//! either directly (for local builds) or via an archive sent over the
+//! network (for remote ones). Either way, the result is the same
+//! `parent_dir/<hash>/` layout (`parent_dir/<hash>/<variant>/` with a
+//! build matrix).
+
+use std::io;
+use std::io::fs;
+use std::io::fs::PathExtensions;
+use std::io::Command as IoCommand;
+use std::{os, str};
+
+use glob;
+
+use build::Job;
+
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct OutputMovement {
+    /// The directory to place the <hash> directory which the
+    /// `to_move` files get placed in.
+    pub parent_dir: String,
+    /// The files/directories to move from the build dir to
+    /// `parent_dir/<hash>/` (or `parent_dir/<hash>/<variant>/`).
+    pub to_move: Vec<String>
+}
+
+impl OutputMovement {
+    /// The directory the output for `job` ends up in, creating it if
+    /// necessary.
+    pub fn hash_dir(&self, job: &Job) -> Path {
+        let suboutput_dir = Path::new(self.parent_dir.as_slice()).join(job.key());
+
+        // create the final output directory.
+        let mkdir = IoCommand::new("mkdir")
+            .arg("-p")
+            .arg(&suboutput_dir)
+            .output().unwrap();
+
+        if !mkdir.status.success() {
+            fail!("mkdir failed on {} with {}",
+                  suboutput_dir.display(),
+                  str::from_utf8(mkdir.error.as_slice()));
+        }
+        suboutput_dir
+    }
+
+    /// Move the output of `job` out of `build_dir` into place, and
+    /// delete the build directory.
+    pub fn move_local(&self, job: &Job, build_dir: &Path) {
+        let suboutput_dir = self.hash_dir(job);
+
+        // move some subdirectory of the final output (in `build_dir`)
+        // to the appropriate place.
+        let mut move_args: Vec<String> = matching_paths(self.to_move.as_slice(), build_dir)
+            .iter()
+            .map(|p| format!("{}", p.display())) // XXX shouldn't be using strings here :(
+            .collect();
+        move_args.push("-vt".to_string());
+        // XXX strings
+        move_args.push(format!("{}", suboutput_dir.display()));
+
+        // move what we want.
+        let mv = IoCommand::new("mv").args(move_args.as_slice()).output().unwrap();
+        if !mv.status.success() {
+            println!("mv: {}", str::from_utf8(mv.output.as_slice()));
+            fail!("mv failed with {}", str::from_utf8(mv.error.as_slice()))
+        }
+
+        remove_build_dir(build_dir);
+    }
+
+    /// Where a (possibly partial) archive of the output of `job` with
+    /// the given checksum is stored while it is being received.
+    pub fn incoming_path(&self, job: &Job, checksum: &str) -> Path {
+        let incoming = Path::new(self.parent_dir.as_slice()).join(".incoming");
+        if !incoming.is_dir() {
+            fs::mkdir_recursive(&incoming, io::USER_RWX).unwrap();
+        }
+        incoming.join(format!("{}-{}.tar", job.file_name(), checksum))
+    }
+
+    /// Unpack a fully received archive made by `pack` into the output
+    /// directory of `job`, and delete it.
+    pub fn unpack(&self, job: &Job, archive: &Path) -> bool {
+        let suboutput_dir = self.hash_dir(job);
+        let tar = IoCommand::new("tar")
+            .arg("-xf").arg(archive)
+            .arg("-C").arg(&suboutput_dir)
+            .output().unwrap();
+        if !tar.status.success() {
+            error!("unpacking {} failed with {}", archive.display(),
+                   str::from_utf8(tar.error.as_slice()));
+            return false
+        }
+        fs::unlink(archive).is_ok()
+    }
+}
+
+/// All the paths in `build_dir` matching one of the `to_move` globs.
+fn matching_paths(to_move: &[String], build_dir: &Path) -> Vec<Path> {
+    to_move.iter().flat_map(|s| {
+        let glob_path = build_dir.join(s.as_slice());
+        let glob_str = format!("{}", glob_path.display());
+        glob::glob(glob_str.as_slice()).collect::<Vec<Path>>().into_iter()
+    }).collect()
+}
+
+/// Delete a directory that a build happened in.
+pub fn remove_build_dir(p: &Path) {
+    let rm = IoCommand::new("rm")
+        .arg("-rf")
+        .arg(p)
+        .output().unwrap();
+
+    if !rm.status.success() {
+        println!("rm: {}", str::from_utf8(rm.output.as_slice()));
+        fail!("rm failed on {} with {}", p.display(),
+              str::from_utf8(rm.error.as_slice()));
+    }
+}
+
+/// Put everything in `build_dir` matching `to_move` into a tar
+/// archive at `archive`, such that unpacking it into a directory gives
+/// the same result as `move_local` into that directory.
+pub fn pack(to_move: &[String], build_dir: &Path, archive: &Path) -> bool {
+    // each -C is relative to the previous one, so make them absolute.
+    let cwd = os::getcwd();
+    let mut tar = IoCommand::new("tar");
+    tar.arg("-cf").arg(archive);
+    for p in matching_paths(to_move, build_dir).iter() {
+        tar.arg("-C").arg(cwd.join(p.dir_path())).arg(p.filename().unwrap());
+    }
+
+    let tar = tar.output().unwrap();
+    if !tar.status.success() {
+        error!("packing {} failed with {}", archive.display(),
+               str::from_utf8(tar.error.as_slice()));
+    }
+    tar.status.success()
+}
+
+/// The SHA-256 of the file at `p`, in hex. `None` on failure.
+pub fn checksum(p: &Path) -> Option<String> {
+    let sum = IoCommand::new("sha256sum").arg(p).output().unwrap();
+    if !sum.status.success() {
+        warn!("sha256sum failed on {}: {}", p.display(),
+              str::from_utf8(sum.error.as_slice()));
+        return None
+    }
+    str::from_utf8(sum.output.as_slice())
+        .and_then(|s| s.words().next())
+        .map(|s| s.to_string())
+}
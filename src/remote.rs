@@ -0,0 +1,1027 @@
+//! Building on other machines. The coordinator listens on a TCP port
+//! and each connection is treated as one more `TaskWorker`; the other
+//! end is a `multibuilder worker` process which builds whatever it is
+//! told to with its own checkout of the repository.
+//!
+//! The protocol is one JSON-encoded message per line, except for the
+//! raw bytes of artifact archives, which follow an `Upload`/`Resume`
+//! exchange.
+//!
+//! After `Setup`, a worker first discards the artifacts of any builds
+//! whose connection was lost before they were uploaded (they've been
+//! handed out again), and then says it's `Ready`; the coordinator
+//! doesn't send it a `Build` before that. The coordinator only takes
+//! results and artifacts for the build it last sent on a connection.
+//!
+//! Workers send a `Heartbeat` regularly; if the coordinator hears
+//! nothing from one for a whole lease, the connection is dropped and
+//! the commit it was building goes back in the queue.
+//!
+//! If the coordinator has a secret, the two ends prove to each other
+//! that they know the worker's key (see `auth`) straight after
+//! `Hello`, and every message after that is prefixed with a MAC under
+//! a key for that session. Archive bytes aren't MACed, but they are
+//! checked against the checksum in the (MACed) `Upload` message.
+
+use std::collections::HashSet;
+use std::{cmp, comm, task};
+use std::io;
+use std::io::{Acceptor, Append, BufferedReader, File, IoError, IoResult, Listener, Write};
+use std::io::fs;
+use std::io::fs::PathExtensions;
+use std::io::net::tcp::{TcpListener, TcpStream};
+use std::io::timer::Timer;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicInt, SeqCst};
+use std::time::Duration;
+
+use serialize::{json, Decodable, Encodable};
+use serialize::hex::ToHex;
+use getopts;
+use libc;
+use time;
+
+use {Command, Variant};
+use auth;
+use build;
+use build::{BuildResult, BuildSettings, Job, Step};
+use cgroup::Usage;
+use coordinator;
+use coordinator::Event;
+use git::Repo;
+use output;
+use output::OutputMovement;
+use signals;
+use signals::{ChildProcesses, Signal, Interrupt, Terminate, HangUp};
+use task_worker;
+use task_worker::TaskWorker;
+
+/// A host and port to listen on or connect to.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct Address {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Address {
+    /// Parse `host:port`.
+    pub fn parse(s: &str) -> Option<Address> {
+        let mut parts = s.rsplitn(':', 1);
+        let port = parts.next().and_then(from_str);
+        let host = parts.next();
+        match (host, port) {
+            (Some(host), Some(port)) => Some(Address { host: host.to_string(), port: port }),
+            _ => None
+        }
+    }
+}
+
+/// How many times a worker tries to upload an archive that arrives
+/// corrupted.
+static UPLOAD_ATTEMPTS: uint = 3;
+
+/// The longest a worker waits between attempts to reconnect to the
+/// coordinator.
+static MAX_RECONNECT_DELAY_SECS: i64 = 60;
+
+/// How long builds on an interrupted worker get to exit after
+/// `SIGTERM` before being killed.
+static WORKER_SHUTDOWN_GRACE_SECS: i64 = 10;
+
+/// How long a newly connected worker gets to say `Hello` and
+/// authenticate before it's dropped.
+static HANDSHAKE_SECS: u64 = 30;
+
+/// Everything a worker needs to know to do builds.
+#[deriving(Encodable, Decodable, Clone, Show)]
+pub struct WorkerSetup {
+    pub build_commands: Vec<Command>,
+    /// The build matrix, if there is one.
+    pub variants: Vec<Variant>,
+    /// The `to_move` globs of the artifacts to upload after a
+    /// successful build. `None` for no uploading.
+    pub to_move: Option<Vec<String>>,
+    /// How often to send a `Heartbeat`.
+    pub heartbeat_secs: i64,
+    /// How to do builds. The `logs.dir` is left to the worker, so they
+    /// go in its build directory.
+    pub settings: BuildSettings,
+}
+
+/// Messages from the coordinator to a worker.
+#[deriving(Encodable, Decodable, Show)]
+pub enum ToWorker {
+    /// Reply to `Hello` if the coordinator requires authentication:
+    /// the coordinator's nonce and its proof of knowing the worker's
+    /// key.
+    Challenge(String, String),
+    /// Sent once, after the worker says hello (and authenticates).
+    Setup(WorkerSetup),
+    Build(Job),
+    /// Reply to `Upload`: send the archive starting from this byte.
+    Resume(u64),
+    /// The archive for the given build was received and unpacked
+    /// (`true`), or was corrupted and discarded (`false`).
+    Received(Job, bool),
+}
+
+/// Messages from a worker to the coordinator.
+#[deriving(Encodable, Decodable, Show)]
+pub enum FromWorker {
+    /// The first message on a connection, containing the worker's
+    /// name and a nonce.
+    Hello(String, String),
+    /// Reply to `Challenge`: the worker's proof of knowing its key.
+    Response(String),
+    /// The given build succeeded, in the given directory on the
+    /// worker, with the given steps, using the given resources (if
+    /// measured).
+    Built(Job, String, Vec<Step>, Option<Usage>),
+    /// The given build didn't succeed: the status is as given by
+    /// `build::Outcome::status`.
+    Unsuccessful(Job, String, Vec<Step>, Option<Usage>),
+    /// An archive of the artifacts of the given build, with the given
+    /// size and SHA-256, is ready to be sent.
+    Upload(Job, u64, String),
+    /// Sent once, after `Setup` and clearing away anything left over
+    /// from an earlier connection: the worker is ready to `Build`.
+    Ready,
+    /// Still alive.
+    Heartbeat,
+}
+
+/// When a worker was last heard from, in seconds since the epoch; or
+/// negative once its connection has closed normally.
+struct Lease {
+    last_heard: AtomicInt,
+}
+
+impl Lease {
+    fn new() -> Lease {
+        let lease = Lease { last_heard: AtomicInt::new(0) };
+        lease.renew();
+        lease
+    }
+    fn renew(&self) {
+        self.last_heard.store(time::get_time().sec as int, SeqCst)
+    }
+    fn close(&self) {
+        self.last_heard.store(-1, SeqCst)
+    }
+}
+
+fn protocol_error(detail: String) -> IoError {
+    IoError {
+        kind: io::InvalidInput,
+        desc: "multibuilder protocol error",
+        detail: Some(detail),
+    }
+}
+
+fn auth_error(detail: String) -> IoError {
+    IoError {
+        kind: io::PermissionDenied,
+        desc: "authentication failed",
+        detail: Some(detail),
+    }
+}
+
+/// The MAC of the `seq`th message sent in one direction of a session.
+fn message_mac(key: &[u8], seq: u64, msg: &str) -> [u8, ..32] {
+    auth::hmac(key, format!("{}:{}", seq, msg).as_bytes())
+}
+
+/// The sending half of a connection.
+struct MessageWriter {
+    stream: TcpStream,
+    /// The session key, once authenticated.
+    key: Option<Vec<u8>>,
+    seq: u64,
+}
+
+impl MessageWriter {
+    fn new(stream: TcpStream) -> MessageWriter {
+        MessageWriter { stream: stream, key: None, seq: 0 }
+    }
+
+    fn send<'a, T: Encodable<json::Encoder<'a>, IoError>>(&mut self, msg: &T) -> IoResult<()> {
+        let msg = json::encode(msg);
+        let line = match self.key {
+            None => format!("{}\n", msg),
+            Some(ref key) => {
+                let mac = message_mac(key.as_slice(), self.seq, msg.as_slice());
+                self.seq += 1;
+                format!("{} {}\n", mac.to_hex(), msg)
+            }
+        };
+        try!(self.stream.write(line.as_bytes()));
+        self.stream.flush()
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> IoResult<()> {
+        self.stream.write(bytes)
+    }
+}
+
+/// The receiving half of a connection.
+struct MessageReader {
+    reader: BufferedReader<TcpStream>,
+    /// The session key, once authenticated.
+    key: Option<Vec<u8>>,
+    seq: u64,
+}
+
+impl MessageReader {
+    fn new(stream: TcpStream) -> MessageReader {
+        MessageReader { reader: BufferedReader::new(stream), key: None, seq: 0 }
+    }
+
+    fn recv<T: Decodable<json::Decoder, json::DecoderError>>(&mut self) -> IoResult<T> {
+        let line = try!(self.reader.read_line());
+        let line = line.as_slice().trim_right_chars('\n');
+        let msg = match self.key {
+            None => line,
+            Some(ref key) => {
+                let (mac, msg) = match line.find(' ') {
+                    Some(i) => (line.slice_to(i), line.slice_from(i + 1)),
+                    None => return Err(auth_error("message without a MAC".to_string()))
+                };
+                let expected = message_mac(key.as_slice(), self.seq, msg);
+                if !auth::verify_hex(&expected, mac) {
+                    return Err(auth_error(format!("bad MAC on message {}", self.seq)))
+                }
+                self.seq += 1;
+                msg
+            }
+        };
+        json::decode(msg).map_err(|e| protocol_error(format!("{}", e)))
+    }
+
+    fn read_raw(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        self.reader.read(buf)
+    }
+
+    /// Fail reads that wait for more than `secs` seconds, or (with
+    /// `None`) wait as long as it takes.
+    fn set_timeout(&mut self, secs: Option<u64>) {
+        self.reader.get_mut_ref().set_read_timeout(secs.map(|s| s * 1000));
+    }
+}
+
+/// Switch both halves of a connection over to MACing messages under
+/// the key for the session identified by the two nonces.
+fn start_session(reader: &mut MessageReader, writer: &mut MessageWriter,
+                 worker_key: &[u8], worker_nonce: &str, coordinator_nonce: &str) {
+    let session = auth::hmac(worker_key,
+                             format!("session:{}:{}", worker_nonce, coordinator_nonce).as_bytes());
+    reader.key = Some(session.to_vec());
+    writer.key = Some(session.to_vec());
+}
+
+fn identity(signal: Signal) -> Signal { signal }
+
+/// Names end up in logs and the history file, so keep them tame.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || "-_./".contains_char(c))
+}
+
+/// A job from a worker ends up in paths, so it has to be one we could
+/// have handed out: a hex hash, and a configured variant (or none, when
+/// there isn't a build matrix).
+fn valid_job(job: &Job, variants: &[Variant]) -> bool {
+    let hash = job.hash.value.as_slice();
+    let hex = !hash.is_empty() && hash.len() <= 64 && hash.chars().all(|c| c.is_digit_radix(16));
+    hex && match job.variant {
+        None => variants.is_empty(),
+        Some(ref name) => Variant::exists(variants, name.as_slice())
+    }
+}
+
+/// A SHA-256 in hex, as `output::checksum` gives.
+fn valid_checksum(checksum: &str) -> bool {
+    checksum.len() == 64 && checksum.chars().all(|c| c.is_digit_radix(16))
+}
+
+/// Whether `job` is the one leased to a worker, ending the lease if
+/// `finished`.
+fn check_lease(leased: &Mutex<Option<Job>>, job: &Job, finished: bool) -> bool {
+    let mut leased = leased.lock();
+    if leased.as_ref() != Some(job) {
+        return false
+    }
+    if finished {
+        *leased = None;
+    }
+    true
+}
+
+/// Start listening for remote workers on `addr`. Each one that
+/// connects is sent `setup` and then handed to the main task via
+/// `events`, looking just like a local worker. Artifacts are placed
+/// according to `output`, and a worker is dropped if it is silent for
+/// longer than `lease_secs`. If `secret` is given, workers have to
+/// authenticate with a key derived from it.
+pub fn listen(addr: Address,
+              setup: WorkerSetup,
+              lease_secs: i64,
+              secret: Option<Vec<u8>>,
+              output: Option<Arc<OutputMovement>>,
+              events: Sender<Event>) {
+    let listener = TcpListener::bind(addr.host.as_slice(), addr.port);
+    let mut acceptor = listener.listen().ok()
+        .expect(format!("couldn't listen on {}:{}", addr.host, addr.port).as_slice());
+    println!("Listening for remote workers on {}:{}", addr.host, addr.port);
+
+    let secret = Arc::new(secret);
+    let hung_up = Arc::new(AtomicBool::new(false));
+    task::spawn(proc() {
+        for stream in acceptor.incoming() {
+            if hung_up.load(SeqCst) {
+                debug!("main task hung up? no longer accepting workers");
+                break
+            }
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => { warn!("accepting a remote worker failed: {}", e); continue }
+            };
+
+            // each in its own task, so that one slow to shake hands
+            // doesn't hold up the rest.
+            let (setup, secret, output) = (setup.clone(), secret.clone(), output.clone());
+            let (events, hung_up) = (events.clone(), hung_up.clone());
+            task::spawn(proc() {
+                match connect_worker(stream, &setup, lease_secs,
+                                     (*secret).as_ref().map(|s| s.as_slice()), output,
+                                     events) {
+                    Ok(true) => {}
+                    Ok(false) => hung_up.store(true, SeqCst),
+                    Err(e) => warn!("remote worker failed to connect: {}", e)
+                }
+            });
+        }
+    });
+}
+
+/// Do the handshake with a newly connected worker, hand the main task
+/// a `TaskWorker` for it, and set up the tasks that translate between
+/// the socket and that. `false` if the main task is no longer
+/// listening.
+fn connect_worker(stream: TcpStream,
+                  setup: &WorkerSetup,
+                  lease_secs: i64,
+                  secret: Option<&[u8]>,
+                  output: Option<Arc<OutputMovement>>,
+                  events: Sender<Event>) -> IoResult<bool> {
+    let mut watched = stream.clone();
+    let mut writer = MessageWriter::new(stream.clone());
+    let mut reader = MessageReader::new(stream);
+    reader.set_timeout(Some(HANDSHAKE_SECS));
+
+    let (name, worker_nonce) = match try!(reader.recv()) {
+        Hello(name, nonce) => (name, nonce),
+        msg => return Err(protocol_error(format!("expected Hello, found {}", msg)))
+    };
+    if !valid_name(name.as_slice()) {
+        return Err(protocol_error(format!("invalid worker name `{}`", name)))
+    }
+
+    match secret {
+        None => {}
+        Some(secret) => {
+            let key = auth::worker_key(secret, name.as_slice());
+            let nonce = auth::nonce();
+            let proof = auth::hmac(&key, format!("coordinator:{}:{}", worker_nonce, nonce).as_bytes());
+            try!(writer.send(&Challenge(nonce.clone(), proof.to_hex())));
+
+            let response = match try!(reader.recv()) {
+                Response(mac) => mac,
+                msg => return Err(protocol_error(format!("expected Response, found {}", msg)))
+            };
+            let expected = auth::hmac(&key, format!("worker:{}:{}", worker_nonce, nonce).as_bytes());
+            if !auth::verify_hex(&expected, response.as_slice()) {
+                return Err(auth_error(format!("worker claiming to be {}", name)))
+            }
+            start_session(&mut reader, &mut writer, &key, worker_nonce.as_slice(), nonce.as_slice());
+        }
+    }
+
+    try!(writer.send(&Setup(setup.clone())));
+    // from now on, silence is up to the lease.
+    reader.set_timeout(None);
+    println!("Remote worker {} connected", name);
+
+    // shared by the two tasks below.
+    let writer = Arc::new(Mutex::new(writer));
+    let replies = writer.clone();
+
+    // the build last sent, the only one results are taken for.
+    let leased = Arc::new(Mutex::new(None));
+    let out_leased = leased.clone();
+    let variants = setup.variants.clone();
+
+    // before anything else can happen on this connection.
+    let (tx, rx) = comm::channel();
+    let id = task_worker::next_id();
+    let worker = TaskWorker::from_sender(tx, Some(name.clone()));
+    if events.send_opt(coordinator::Connected(id, worker)).is_err() {
+        return Ok(false)
+    }
+
+    let lease = Arc::new(Lease::new());
+
+    // expire the lease if the worker goes quiet, by shutting down the
+    // connection so that the reading task below finishes.
+    let watchdog_lease = lease.clone();
+    let watchdog_name = name.clone();
+    task::spawn(proc() {
+        let mut timer = Timer::new().ok().expect("No timer??");
+        loop {
+            timer.sleep(Duration::seconds(cmp::max(1, lease_secs / 4)));
+            let last_heard = watchdog_lease.last_heard.load(SeqCst) as i64;
+            if last_heard < 0 {
+                break
+            }
+            if time::get_time().sec - last_heard > lease_secs {
+                warn!("lease of remote worker {} expired", watchdog_name);
+                let _ = watched.close_read();
+                break
+            }
+        }
+    });
+
+    // held back until the worker is `Ready`.
+    let (ready_tx, ready_rx) = comm::channel();
+
+    // instructions out...
+    task::spawn(proc() {
+        if ready_rx.recv_opt().is_err() {
+            return
+        }
+        for instr in rx.iter() {
+            let build::BuildJob(job) = instr;
+            *out_leased.lock() = Some(job.clone());
+            match writer.lock().send(&Build(job)) {
+                Ok(()) => {}
+                Err(e) => { warn!("sending to remote worker failed: {}", e); break }
+            }
+        }
+    });
+
+    // ... and results back.
+    task::spawn(proc() {
+        let expected = |job: &Job, finished: bool| {
+            valid_job(job, variants.as_slice()) && check_lease(&*leased, job, finished)
+        };
+        loop {
+            let msg = reader.recv();
+            lease.renew();
+            let result = match msg {
+                Ok(Heartbeat) => continue,
+                Ok(Ready) => {
+                    let _ = ready_tx.send_opt(());
+                    continue
+                }
+                Ok(Built(job, dir, steps, usage)) => {
+                    if !expected(&job, true) {
+                        warn!("{} sent a result for {}, which it wasn't given", name, job.key());
+                        break
+                    }
+                    BuildResult {
+                        job: job,
+                        outcome: build::Success(build::Remote(name.clone(), dir)),
+                        steps: steps,
+                        usage: usage,
+                    }
+                }
+                Ok(Unsuccessful(job, status, steps, usage)) => {
+                    if !expected(&job, true) {
+                        warn!("{} sent a result for {}, which it wasn't given", name, job.key());
+                        break
+                    }
+                    match build::Outcome::from_status(status.as_slice()) {
+                        Some(outcome) => {
+                            BuildResult { job: job, outcome: outcome, steps: steps, usage: usage }
+                        }
+                        None => {
+                            warn!("{} sent an unknown status {}", name, status);
+                            break
+                        }
+                    }
+                }
+                Ok(Upload(job, size, checksum)) => {
+                    if !expected(&job, false) || !valid_checksum(checksum.as_slice()) {
+                        warn!("{} sent an unexpected upload of {}", name, job.key());
+                        break
+                    }
+                    match receive_upload(&mut reader, &*replies, &output, &*lease,
+                                         &job, size, checksum.as_slice()) {
+                        Ok(()) => continue,
+                        Err(e) => { warn!("upload from {} failed: {}", name, e); break }
+                    }
+                }
+                Ok(msg) => { warn!("unexpected message from {}: {}", name, msg); break }
+                Err(e) => { warn!("lost remote worker {}: {}", name, e); break }
+            };
+
+            if events.send_opt(coordinator::Finished(id, result)).is_err() {
+                break
+            }
+        }
+        lease.close();
+        let _ = events.send_opt(coordinator::Lost(id));
+    });
+
+    Ok(true)
+}
+
+/// Receive an archive of the artifacts of `job` into the output
+/// directory, resuming from whatever was received by an earlier
+/// (interrupted) attempt.
+fn receive_upload(reader: &mut MessageReader,
+                  replies: &Mutex<MessageWriter>,
+                  output: &Option<Arc<OutputMovement>>,
+                  lease: &Lease,
+                  job: &Job, size: u64, checksum: &str) -> IoResult<()> {
+    let output = match *output {
+        Some(ref o) => o,
+        None => return Err(protocol_error(format!("unexpected upload of {}", job.key())))
+    };
+
+    let partial = output.incoming_path(job, checksum);
+    let mut offset = if partial.exists() { try!(fs::stat(&partial)).size } else { 0 };
+    if offset > size {
+        // can't be the same archive.
+        try!(fs::unlink(&partial));
+        offset = 0;
+    }
+    if offset > 0 {
+        info!("resuming upload of {} at {}/{} bytes", job.key(), offset, size);
+    }
+    try!(replies.lock().send(&Resume(offset)));
+
+    {
+        let mut file = try!(File::open_mode(&partial, Append, Write));
+        let mut remaining = size - offset;
+        let mut buf = [0u8, .. 64 * 1024];
+        while remaining > 0 {
+            let want = cmp::min(remaining, buf.len() as u64) as uint;
+            let n = try!(reader.read_raw(buf.slice_to_mut(want)));
+            // written straight away, so that a dropped connection
+            // leaves as much as possible to resume from.
+            try!(file.write(buf.slice_to(n)));
+            remaining -= n as u64;
+            // a big upload can take longer than a lease.
+            lease.renew();
+        }
+        try!(file.fsync());
+    }
+
+    let ok = match output::checksum(&partial) {
+        Some(ref c) if c.as_slice() == checksum => output.unpack(job, &partial),
+        c => {
+            warn!("archive of {} has checksum {}, expected {}", job.key(), c, checksum);
+            false
+        }
+    };
+    if !ok && partial.exists() {
+        try!(fs::unlink(&partial));
+    }
+    replies.lock().send(&Received(job.clone(), ok))
+}
+
+/// The entry point for `multibuilder worker`.
+pub fn worker_main(program: &str, args: &[String]) {
+    let opts =
+        vec![getopts::reqopt("", "connect", "address of the coordinator", "HOST:PORT"),
+             getopts::reqopt("r", "repo", "local repository to clone from", "PATH"),
+             getopts::optopt("b", "build-dir",
+                             "directory to build in (default ./build)", "PATH"),
+             getopts::optopt("n", "name", "name of this worker (default `worker`)", "NAME"),
+             getopts::optopt("j", "jobs", "number of builds to run at once (default 1)", "N"),
+             getopts::optopt("k", "key-file",
+                             "file containing this worker's key, from `worker-key`", "PATH"),
+             getopts::optflag("h", "help", "show this help message")];
+
+    let usage = || getopts::usage(format!("{} worker", program).as_slice(), opts.as_slice());
+
+    let matches = match getopts::getopts(args, opts.as_slice()) {
+        Err(err) => fail!("{}\n{}", err, usage()),
+        Ok(m) => m
+    };
+    if matches.opt_present("h") {
+        println!("{}", usage());
+        return;
+    }
+
+    let addr_str = matches.opt_str("connect").unwrap();
+    let addr = Address::parse(addr_str.as_slice())
+        .expect(format!("invalid address `{}`", addr_str).as_slice());
+    let repo = Arc::new(Repo::new(Path::new(matches.opt_str("r").unwrap())));
+    let build_dir = Path::new(matches.opt_str("b").unwrap_or("build".to_string()));
+    let name = matches.opt_str("n").unwrap_or("worker".to_string());
+    let jobs = match matches.opt_str("j") {
+        None => 1,
+        Some(j) => from_str(j.as_slice()).expect("--jobs should be a number")
+    };
+    assert!(valid_name(name.as_slice()), "invalid worker name `{}`", name);
+    let key = matches.opt_str("k").map(|k| Arc::new(auth::load_worker_key(&Path::new(k))));
+
+    // being interrupted kills any builds in progress: the coordinator
+    // will notice we've gone, and give them to someone else.
+    let children = Arc::new(ChildProcesses::new());
+    let (signal_tx, signal_rx) = comm::channel();
+    signals::watch(&[Interrupt, Terminate, HangUp], signal_tx, identity);
+    let to_kill = children.clone();
+    task::spawn(proc() {
+        if signal_rx.recv_opt().is_ok() {
+            println!("Interrupted, killing builds in progress.");
+            to_kill.kill_all(WORKER_SHUTDOWN_GRACE_SECS);
+            unsafe { libc::exit(1) }
+        }
+    });
+
+    // the jobs share the build directory, so they need to agree on who
+    // is uploading which archive.
+    let uploading = Arc::new(Mutex::new(HashSet::new()));
+
+    let (done_tx, done_rx) = comm::channel();
+    for i in range(0u, jobs) {
+        let (addr, repo, build_dir, key) = (addr.clone(), repo.clone(), build_dir.clone(), key.clone());
+        let (children, uploading) = (children.clone(), uploading.clone());
+        // all the jobs share the key, so they share the name too, as
+        // far as the coordinator is concerned.
+        let name = if key.is_some() { name.clone() } else { format!("{}/{}", name, i) };
+        let done_tx = done_tx.clone();
+        task::spawn(proc() {
+            let mut timer = Timer::new().ok().expect("No timer??");
+            let mut delay = 1;
+            loop {
+                match work(&addr, name.clone(), key.as_ref().map(|k| k.as_slice()),
+                           &build_dir, &*repo, &*children, &*uploading) {
+                    Ok(()) => { println!("{}: coordinator finished with us", name); break }
+                    Err(e) => error!("{}: {}", name, e)
+                }
+                // try again, in case the coordinator or the network
+                // just hiccuped.
+                println!("{}: reconnecting in {}s", name, delay);
+                timer.sleep(Duration::seconds(delay));
+                delay = cmp::min(delay * 2, MAX_RECONNECT_DELAY_SECS);
+            }
+            done_tx.send(());
+        });
+    }
+    for _ in range(0u, jobs) {
+        done_rx.recv();
+    }
+}
+
+/// The entry point for `multibuilder worker-key`, which prints the key
+/// for the worker with the given name.
+pub fn worker_key_main(program: &str, args: &[String]) {
+    let opts =
+        vec![getopts::reqopt("s", "secret-file", "the coordinator's `secret_file`", "PATH"),
+             getopts::optflag("h", "help", "show this help message")];
+    let usage = || getopts::usage(format!("{} worker-key NAME", program).as_slice(),
+                                  opts.as_slice());
+
+    let matches = match getopts::getopts(args, opts.as_slice()) {
+        Err(err) => fail!("{}\n{}", err, usage()),
+        Ok(m) => m
+    };
+    if matches.opt_present("h") || matches.free.len() != 1 {
+        println!("{}", usage());
+        return;
+    }
+
+    let name = matches.free[0].as_slice();
+    assert!(valid_name(name), "invalid worker name `{}`", name);
+    let secret = auth::load_secret(&Path::new(matches.opt_str("s").unwrap()));
+    println!("{}", auth::worker_key(secret.as_slice(), name).to_hex());
+}
+
+/// Connect to the coordinator and build what it tells us to until it
+/// hangs up. `uploading` is shared by all the jobs in this process,
+/// as for `claim`.
+fn work(addr: &Address, name: String, key: Option<&[u8]>,
+        build_dir: &Path, repo: &Repo, children: &ChildProcesses,
+        uploading: &Mutex<HashSet<String>>) -> IoResult<()> {
+    let stream = try!(TcpStream::connect(addr.host.as_slice(), addr.port));
+    let mut writer = MessageWriter::new(stream.clone());
+    let mut reader = MessageReader::new(stream);
+
+    let nonce = auth::nonce();
+    try!(writer.send(&Hello(name, nonce.clone())));
+    let setup = match (try!(reader.recv()), key) {
+        (Challenge(coordinator_nonce, proof), Some(key)) => {
+            let expected = auth::hmac(key, format!("coordinator:{}:{}",
+                                                   nonce, coordinator_nonce).as_bytes());
+            if !auth::verify_hex(&expected, proof.as_slice()) {
+                return Err(auth_error("the coordinator doesn't know our key".to_string()))
+            }
+            let response = auth::hmac(key, format!("worker:{}:{}",
+                                                   nonce, coordinator_nonce).as_bytes());
+            try!(writer.send(&Response(response.to_hex())));
+            start_session(&mut reader, &mut writer, key, nonce.as_slice(),
+                          coordinator_nonce.as_slice());
+            try!(reader.recv())
+        }
+        (Challenge(..), None) => {
+            return Err(auth_error("the coordinator requires a key (--key-file)".to_string()))
+        }
+        (_, Some(_)) => {
+            // it could be anyone, and we're about to run whatever it
+            // tells us to.
+            return Err(auth_error("the coordinator didn't authenticate itself".to_string()))
+        }
+        (msg, None) => msg
+    };
+    let WorkerSetup { build_commands, variants, to_move, heartbeat_secs, settings } = match setup {
+        Setup(setup) => setup,
+        msg => return Err(protocol_error(format!("expected Setup, found {}", msg)))
+    };
+
+    // shared with the heartbeat task.
+    let writer = Arc::new(Mutex::new(writer));
+
+    // dropped when we return, stopping the heartbeats.
+    let (_stop_heartbeat, stop) = comm::channel::<()>();
+    let heartbeat_writer = writer.clone();
+    task::spawn(proc() {
+        let mut timer = Timer::new().ok().expect("No timer??");
+        loop {
+            timer.sleep(Duration::seconds(heartbeat_secs));
+            match stop.try_recv() {
+                Err(comm::Disconnected) => break,
+                _ => {}
+            }
+            if heartbeat_writer.lock().send(&Heartbeat).is_err() {
+                break
+            }
+        }
+    });
+
+    // clear away anything whose connection was lost, before asking
+    // for anything new. The coordinator has already given those builds
+    // to someone else, so it won't take their artifacts from us.
+    if to_move.is_some() && build_dir.is_dir() {
+        for archive in try!(fs::readdir(build_dir)).into_iter() {
+            if archive.extension_str() != Some("tar") {
+                continue
+            }
+            let _claim = match claim(uploading, &archive) {
+                Some(c) => c,
+                None => continue
+            };
+            // another job might've cleared it in the meantime.
+            if !archive.exists() {
+                continue
+            }
+            let job = Job::from_file_name(archive.filestem_str().unwrap());
+            info!("discarding the unsent artifacts of {}", job.key());
+            try!(fs::unlink(&archive));
+            output::remove_build_dir(&build_dir.join(job.file_name()));
+        }
+    }
+    try!(writer.lock().send(&Ready));
+
+    loop {
+        let job = match reader.recv() {
+            Ok(Build(job)) => job,
+            Ok(msg) => return Err(protocol_error(format!("expected Build, found {}", msg))),
+            Err(ref e) if e.kind == io::EndOfFile => return Ok(()),
+            Err(e) => return Err(e)
+        };
+
+        let BuildResult { job, outcome, steps, usage } =
+            task_worker::build_commit(build_dir, repo, build_commands.as_slice(),
+                                      variants.as_slice(), &settings, None, children, job);
+        let msg = match outcome {
+            build::Success(build::Local(p)) => {
+                match to_move {
+                    None => {}
+                    Some(ref to_move) => {
+                        // kept next to the build directories until
+                        // it is safely received.
+                        let archive = build_dir.join(format!("{}.tar", job.file_name()));
+                        let _claim = claim(uploading, &archive);
+                        if !output::pack(to_move.as_slice(), &p, &archive) {
+                            return Err(IoError {
+                                kind: io::OtherIoError,
+                                desc: "couldn't pack artifacts",
+                                detail: Some(job.key()),
+                            })
+                        }
+                        try!(upload(&mut reader, &*writer, &job, &archive));
+                        output::remove_build_dir(&p);
+                    }
+                }
+                Built(job, format!("{}", p.display()), steps, usage)
+            }
+            build::Success(loc) => fail!("local build of {} ended up at {}", job.key(), loc),
+            build::Failure => Unsuccessful(job, "failure".to_string(), steps, usage),
+            outcome => {
+                output::remove_build_dir(&build_dir.join(job.file_name()));
+                Unsuccessful(job, outcome.status().to_string(), steps, usage)
+            }
+        };
+        try!(writer.lock().send(&msg));
+    }
+}
+
+/// An archive that one of the jobs in this process is uploading, so
+/// that no other job picks it up too; given up when dropped.
+struct Claim<'a> {
+    claimed: &'a Mutex<HashSet<String>>,
+    archive: String,
+}
+
+impl<'a> Drop for Claim<'a> {
+    fn drop(&mut self) {
+        self.claimed.lock().remove(&self.archive);
+    }
+}
+
+/// Claim `archive` for uploading. `None` if another job already has.
+fn claim<'a>(claimed: &'a Mutex<HashSet<String>>, archive: &Path) -> Option<Claim<'a>> {
+    let archive = format!("{}", archive.display());
+    if !claimed.lock().insert(archive.clone()) {
+        return None
+    }
+    Some(Claim { claimed: claimed, archive: archive })
+}
+
+/// Send the archive of the artifacts of `job` to the coordinator,
+/// deleting it once it has been received intact.
+fn upload(reader: &mut MessageReader, writer: &Mutex<MessageWriter>,
+          job: &Job, archive: &Path) -> IoResult<()> {
+    let checksum = match output::checksum(archive) {
+        Some(c) => c,
+        None => return Err(IoError {
+            kind: io::OtherIoError,
+            desc: "couldn't checksum archive",
+            detail: Some(format!("{}", archive.display())),
+        })
+    };
+    let size = try!(fs::stat(archive)).size;
+
+    for attempt in range(0, UPLOAD_ATTEMPTS) {
+        {
+            // no heartbeats in the middle of the archive, please.
+            let mut writer = writer.lock();
+
+            try!(writer.send(&Upload(job.clone(), size, checksum.clone())));
+            let offset = match try!(reader.recv()) {
+                Resume(offset) => offset,
+                msg => return Err(protocol_error(format!("expected Resume, found {}", msg)))
+            };
+
+            let mut file = try!(File::open(archive));
+            try!(file.seek(offset as i64, io::SeekSet));
+            let mut buf = [0u8, .. 64 * 1024];
+            loop {
+                match file.read(&mut buf) {
+                    Ok(n) => try!(writer.write_raw(buf.slice_to(n))),
+                    Err(ref e) if e.kind == io::EndOfFile => break,
+                    Err(e) => return Err(e)
+                }
+            }
+            try!(writer.stream.flush());
+        }
+
+        match try!(reader.recv()) {
+            Received(ref j, true) if j == job => return fs::unlink(archive),
+            Received(ref j, false) if j == job => {
+                warn!("upload of {} was corrupted (attempt {}/{})",
+                      job.key(), attempt + 1, UPLOAD_ATTEMPTS);
+            }
+            msg => return Err(protocol_error(format!("expected Received, found {}", msg)))
+        }
+    }
+
+    Err(IoError {
+        kind: io::OtherIoError,
+        desc: "upload repeatedly corrupted",
+        detail: Some(job.key()),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use serialize::json;
+
+    use build;
+    use build::{Job, Step, test_job};
+    use git::Sha;
+    use super::{Address, valid_name, valid_job, valid_checksum, message_mac};
+    use super::{ToWorker, Build, Received};
+    use super::{FromWorker, Built, Unsuccessful, Upload};
+
+    #[test]
+    fn parse_address() {
+        let addr = Address::parse("example.com:8080").unwrap();
+        assert_eq!(addr.host.as_slice(), "example.com");
+        assert_eq!(addr.port, 8080);
+
+        // the port is after the last colon.
+        let addr = Address::parse("::1:80").unwrap();
+        assert_eq!(addr.host.as_slice(), "::1");
+        assert_eq!(addr.port, 80);
+
+        assert!(Address::parse("example.com").is_none());
+        assert!(Address::parse("example.com:http").is_none());
+        assert!(Address::parse("example.com:65536").is_none());
+    }
+
+    #[test]
+    fn worker_names() {
+        assert!(valid_name("worker"));
+        assert!(valid_name("build-box_2.example/0"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("two words"));
+        assert!(!valid_name("colon:ised"));
+        assert!(!valid_name("new\nline"));
+    }
+
+    #[test]
+    fn jobs_and_checksums_from_workers() {
+        assert!(valid_job(&test_job(None), []));
+        // not a variant we have.
+        assert!(!valid_job(&test_job(Some("debug")), []));
+        let escape = Job::new(Sha { value: "../../etc".to_string() }, None);
+        assert!(!valid_job(&escape, []));
+
+        let sum = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        assert!(valid_checksum(sum));
+        assert!(!valid_checksum(sum.slice_from(1)));
+        assert!(!valid_checksum("../../../../../../../../../../../../../../../../../../../../x"));
+    }
+
+    #[test]
+    fn to_worker_round_trip() {
+        let line = json::encode(&Build(test_job(Some("debug"))));
+        match json::decode::<ToWorker>(line.as_slice()) {
+            Ok(Build(j)) => assert_eq!(j, test_job(Some("debug"))),
+            other => fail!("{}", other)
+        }
+
+        let line = json::encode(&Received(test_job(None), true));
+        match json::decode::<ToWorker>(line.as_slice()) {
+            Ok(Received(j, ok)) => { assert_eq!(j, test_job(None)); assert!(ok) }
+            other => fail!("{}", other)
+        }
+    }
+
+    #[test]
+    fn from_worker_round_trip() {
+        let step = Step {
+            command: "make".to_string(),
+            outcome: build::Exited(2),
+            duration_ms: 1234,
+            log: Some("build/logs/0123456789abcdef.log".to_string()),
+            env: None,
+            resources: None,
+        };
+        let line = json::encode(&Unsuccessful(test_job(None), "failure".to_string(),
+                                              vec![step.clone()], None));
+        match json::decode::<FromWorker>(line.as_slice()) {
+            Ok(Unsuccessful(j, status, steps, usage)) => {
+                assert_eq!(j, test_job(None));
+                assert_eq!(status.as_slice(), "failure");
+                assert_eq!(steps.len(), 1);
+                assert_eq!(steps[0].outcome, step.outcome);
+                assert_eq!(steps[0].duration_ms, 1234);
+                assert!(usage.is_none());
+            }
+            other => fail!("{}", other)
+        }
+
+        let line = json::encode(&Built(test_job(Some("release")), "build/x".to_string(),
+                                       vec![], None));
+        match json::decode::<FromWorker>(line.as_slice()) {
+            Ok(Built(j, dir, steps, _)) => {
+                assert_eq!(j, test_job(Some("release")));
+                assert_eq!(dir.as_slice(), "build/x");
+                assert!(steps.is_empty());
+            }
+            other => fail!("{}", other)
+        }
+
+        let line = json::encode(&Upload(test_job(None), 1 << 40, "ab".to_string()));
+        match json::decode::<FromWorker>(line.as_slice()) {
+            Ok(Upload(j, size, sum)) => {
+                assert_eq!(j, test_job(None));
+                assert_eq!(size, 1 << 40);
+                assert_eq!(sum.as_slice(), "ab");
+            }
+            other => fail!("{}", other)
+        }
+    }
+
+    #[test]
+    fn message_macs_depend_on_sequence_number() {
+        let key = b"session key";
+        assert_eq!(message_mac(key, 0, "{}").as_slice(), message_mac(key, 0, "{}").as_slice());
+        assert!(message_mac(key, 0, "{}").as_slice() != message_mac(key, 1, "{}").as_slice());
+        assert!(message_mac(key, 0, "{}").as_slice() != message_mac(key, 0, "[]").as_slice());
+    }
+}
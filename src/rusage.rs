@@ -0,0 +1,93 @@
+//! Waiting for build commands with `wait4`, which says what they used
+//! as well as how they ended.
+
+use std::io::IoResult;
+use std::io::IoError;
+use std::io::process::{ExitSignal, ExitStatus, ProcessExit};
+use std::mem;
+use std::os;
+
+use libc;
+use libc::{c_int, c_long, pid_t};
+
+static WNOHANG: c_int = 1;
+
+#[repr(C)]
+struct timeval {
+    tv_sec: c_long,
+    tv_usec: c_long,
+}
+
+#[repr(C)]
+struct rusage {
+    ru_utime: timeval,
+    ru_stime: timeval,
+    ru_maxrss: c_long,
+    ru_ixrss: c_long,
+    ru_idrss: c_long,
+    ru_isrss: c_long,
+    ru_minflt: c_long,
+    ru_majflt: c_long,
+    ru_nswap: c_long,
+    ru_inblock: c_long,
+    ru_oublock: c_long,
+    ru_msgsnd: c_long,
+    ru_msgrcv: c_long,
+    ru_nsignals: c_long,
+    ru_nvcsw: c_long,
+    ru_nivcsw: c_long,
+}
+
+extern {
+    fn wait4(pid: pid_t, status: *mut c_int, options: c_int, rusage: *mut rusage) -> pid_t;
+}
+
+/// What a command (and everything it waited for) used.
+#[deriving(Encodable, Decodable, Clone, Show, PartialEq)]
+pub struct Resources {
+    /// CPU time in user mode, in milliseconds.
+    pub user_ms: i64,
+    /// CPU time in the kernel, in milliseconds.
+    pub sys_ms: i64,
+    /// The largest resident set size of any one process, in KiB.
+    pub max_rss_kb: i64,
+}
+
+impl Resources {
+    /// CPU times and peak RSS, as shown after each step.
+    pub fn describe(&self) -> String {
+        format!("user {}.{:03}s, sys {}.{:03}s, max RSS {:.1} MiB",
+                self.user_ms / 1000, self.user_ms % 1000,
+                self.sys_ms / 1000, self.sys_ms % 1000,
+                self.max_rss_kb as f64 / 1024.0)
+    }
+}
+
+fn millis(t: &timeval) -> i64 {
+    t.tv_sec as i64 * 1000 + t.tv_usec as i64 / 1000
+}
+
+/// If the child `pid` has exited, reap it, returning how it ended and
+/// what it used; `None` if it's still running.
+pub fn try_wait(pid: pid_t) -> IoResult<Option<(ProcessExit, Resources)>> {
+    let mut status = 0;
+    let mut usage: rusage = unsafe { mem::zeroed() };
+    let ret = unsafe { wait4(pid, &mut status, WNOHANG, &mut usage) };
+    if ret == 0 || (ret < 0 && os::errno() as c_int == libc::EINTR) {
+        return Ok(None)
+    }
+    if ret < 0 {
+        return Err(IoError::last_error())
+    }
+
+    let exit = if status & 0x7f == 0 {
+        ExitStatus(((status >> 8) & 0xff) as int)
+    } else {
+        ExitSignal((status & 0x7f) as int)
+    };
+    Ok(Some((exit, Resources {
+        user_ms: millis(&usage.ru_utime),
+        sys_ms: millis(&usage.ru_stime),
+        max_rss_kb: usage.ru_maxrss as i64,
+    })))
+}
@@ -0,0 +1,364 @@
+//! Turning signals into messages, and keeping track of the processes
+//! that builds have started so that they can be stopped.
+//!
+//! Build commands are started in their own process groups, so that a
+//! ^C at the terminal doesn't kill them; it's up to us to decide when
+//! they should die.
+
+use std::collections::{HashMap, HashSet};
+use std::io::signal;
+use std::io::signal::{Listener, Signum};
+use std::io::timer::Timer;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicInt, INIT_ATOMIC_INT, SeqCst};
+use std::{comm, mem, os, ptr};
+use std::task;
+use std::time::Duration;
+
+use libc::{c_int, c_void, pid_t, size_t};
+use libc::consts::os::posix88::{EINTR, EPERM};
+use libc::funcs::posix88::signal::kill;
+use libc::funcs::posix88::unistd::{pipe, read, write};
+
+pub use libc::consts::os::posix88::{SIGKILL, SIGTERM};
+
+use build::Job;
+
+/// How long a command's output gets to end once it has exited: if
+/// anything it left running in the background still has the pipes
+/// open after that, its process group is killed and we stop waiting.
+pub static OUTPUT_GRACE_SECS: i64 = 5;
+
+/// The signals that can be watched for.
+#[deriving(PartialEq, Show)]
+pub enum Signal {
+    /// `SIGINT`.
+    Interrupt,
+    /// `SIGTERM`.
+    Terminate,
+    /// `SIGHUP`.
+    HangUp,
+    /// `SIGUSR1`.
+    User1,
+    /// `SIGUSR2`.
+    User2,
+}
+
+impl Signal {
+    /// What `Listener` calls it, or the signal number for those it
+    /// can't catch.
+    fn signum(&self) -> Result<Signum, c_int> {
+        match *self {
+            Interrupt => Ok(signal::Interrupt),
+            Terminate => Err(SIGTERM),
+            HangUp => Ok(signal::HangUp),
+            User1 => Ok(signal::User1),
+            User2 => Ok(signal::User2),
+        }
+    }
+
+    fn from_signum(signum: Signum) -> Option<Signal> {
+        match signum {
+            signal::Interrupt => Some(Interrupt),
+            signal::HangUp => Some(HangUp),
+            signal::User1 => Some(User1),
+            signal::User2 => Some(User2),
+            _ => None
+        }
+    }
+
+    fn from_number(number: c_int) -> Option<Signal> {
+        if number == SIGTERM { Some(Terminate) } else { None }
+    }
+}
+
+/// Catch the given signals, and send `wrap(signal)` down `tx` when
+/// one arrives. A signal that can't be caught is left alone, with a
+/// warning.
+pub fn watch<T: Send>(signals: &[Signal], tx: Sender<T>, wrap: fn(Signal) -> T) {
+    let mut listener = Listener::new();
+    let mut by_hand = Vec::new();
+    for &signal in signals.iter() {
+        match signal.signum() {
+            Ok(signum) => match listener.register(signum) {
+                Ok(()) => {}
+                Err(e) => warn!("couldn't catch {}: {}", signal, e)
+            },
+            Err(number) => by_hand.push(number)
+        }
+    }
+    if !by_hand.is_empty() {
+        catch_by_hand(by_hand.as_slice(), tx.clone(), wrap);
+    }
+
+    task::spawn(proc() {
+        for signum in listener.rx.iter() {
+            let signal = match Signal::from_signum(signum) {
+                Some(s) => s,
+                None => continue
+            };
+            if tx.send_opt(wrap(signal)).is_err() {
+                return
+            }
+        }
+    });
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct SigAction {
+    sa_handler: size_t,
+    // a `sigset_t`: 1024 bits.
+    sa_mask: [u32, ..32],
+    sa_flags: c_int,
+    sa_restorer: *mut c_void,
+}
+
+#[cfg(target_os = "linux")]
+static SA_RESTART: c_int = 0x10000000;
+
+#[cfg(target_os = "macos")]
+#[repr(C)]
+struct SigAction {
+    sa_handler: size_t,
+    sa_mask: u32,
+    sa_flags: c_int,
+}
+
+#[cfg(target_os = "freebsd")]
+#[repr(C)]
+struct SigAction {
+    sa_handler: size_t,
+    sa_flags: c_int,
+    sa_mask: [u32, ..4],
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+static SA_RESTART: c_int = 0x0002;
+
+extern {
+    fn sigaction(signum: c_int, act: *const SigAction, oldact: *mut SigAction) -> c_int;
+}
+
+/// The write end of the pipe `on_signal` reports signals down.
+static SELF_PIPE: AtomicInt = INIT_ATOMIC_INT;
+
+extern "C" fn on_signal(number: c_int) {
+    // only async-signal-safe things in here: a single write(2).
+    let byte = number as u8;
+    unsafe { write(SELF_PIPE.load(SeqCst) as c_int, &byte as *const u8 as *const c_void, 1); }
+}
+
+/// Catch the signals `numbers` with `sigaction`, for those `Listener`
+/// doesn't know about: the handler writes each one's number down a
+/// pipe, which a task reads from and sends on as `watch` does.
+fn catch_by_hand<T: Send>(numbers: &[c_int], tx: Sender<T>, wrap: fn(Signal) -> T) {
+    let mut fds = [0 as c_int, ..2];
+    if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+        warn!("couldn't catch signals {}: no pipe: {}", numbers, os::last_os_error());
+        return
+    }
+    SELF_PIPE.store(fds[1] as int, SeqCst);
+    for &number in numbers.iter() {
+        // zeroed, the mask is empty.
+        let mut action: SigAction = unsafe { mem::zeroed() };
+        action.sa_handler = on_signal as size_t;
+        action.sa_flags = SA_RESTART;
+        if unsafe { sigaction(number, &action, ptr::null_mut()) } != 0 {
+            warn!("couldn't catch signal {}: {}", number, os::last_os_error());
+        }
+    }
+
+    let read_end = fds[0];
+    task::spawn(proc() {
+        loop {
+            let mut byte = 0u8;
+            let n = unsafe { read(read_end, &mut byte as *mut u8 as *mut c_void, 1) };
+            if n < 0 && os::errno() as c_int == EINTR {
+                continue
+            }
+            if n != 1 {
+                return
+            }
+            match Signal::from_number(byte as c_int) {
+                Some(signal) => {
+                    if tx.send_opt(wrap(signal)).is_err() {
+                        return
+                    }
+                }
+                None => {}
+            }
+        }
+    });
+}
+
+/// Send `sig` to every process in the group led by `pid`.
+pub fn signal_group(pid: pid_t, sig: c_int) {
+    debug!("sending signal {} to process group {}", sig, pid);
+    unsafe { kill(-pid, sig); }
+}
+
+/// Wait for each of `streams` to say that the output of the command
+/// led by `pid`, which has exited, has ended. `false` if something
+/// still held it open after `OUTPUT_GRACE_SECS`: its process group has
+/// been killed, and nothing should wait for the output any more.
+pub fn await_output(pid: pid_t, mut streams: Vec<Receiver<()>>) -> bool {
+    // check every tenth of a second.
+    let mut timer = Timer::new().ok().expect("No timer??");
+    let mut ticks = 0;
+    loop {
+        streams.retain(|rx| rx.try_recv() == Err(comm::Empty));
+        if streams.is_empty() {
+            return true
+        }
+        if ticks >= OUTPUT_GRACE_SECS * 10 {
+            break
+        }
+        timer.sleep(Duration::milliseconds(100));
+        ticks += 1;
+    }
+    signal_group(pid, SIGKILL);
+    false
+}
+
+/// Whether the process `pid` is still running (as far as we can
+/// tell: it might be someone else's by now).
+pub fn is_alive(pid: pid_t) -> bool {
+    // signal 0 only checks that it could be sent.
+    unsafe { kill(pid, 0) == 0 } || os::errno() as c_int == EPERM
+}
+
+/// The process groups of all the build commands (and the git commands
+/// setting builds up) currently running, and the builds they're part
+/// of, along with which builds have been killed.
+pub struct ChildProcesses {
+    state: Mutex<Children>,
+}
+
+struct Children {
+    pids: HashMap<pid_t, Job>,
+    /// Builds being killed: they mustn't start anything else, even
+    /// when they have nothing running at the moment.
+    killed: HashSet<Job>,
+    /// Whether every build is being killed, including ones that
+    /// haven't started yet.
+    killing_all: bool,
+}
+
+impl ChildProcesses {
+    pub fn new() -> ChildProcesses {
+        ChildProcesses {
+            state: Mutex::new(Children {
+                pids: HashMap::new(),
+                killed: HashSet::new(),
+                killing_all: false,
+            })
+        }
+    }
+
+    /// Start tracking `pid`, which must be a process group leader, as
+    /// part of `job`. If `job` has already been killed, `pid` is
+    /// killed straight away: it started too late to get any grace.
+    pub fn add(&self, pid: pid_t, job: &Job) {
+        let mut state = self.state.lock();
+        if state.killing_all || state.killed.contains(job) {
+            signal_group(pid, SIGKILL);
+        }
+        state.pids.insert(pid, job.clone());
+    }
+
+    pub fn remove(&self, pid: pid_t) {
+        self.state.lock().pids.remove(&pid);
+    }
+
+    /// Whether `job` has been killed, so shouldn't run anything more.
+    pub fn is_killed(&self, job: &Job) -> bool {
+        let state = self.state.lock();
+        state.killing_all || state.killed.contains(job)
+    }
+
+    /// Forget that `job` was killed, once it has finished, so that it
+    /// can be built again later.
+    pub fn forget_killed(&self, job: &Job) {
+        self.state.lock().killed.remove(job);
+    }
+
+    /// Send `sig` to every process in the groups of the builds that
+    /// `which` picks.
+    fn signal(&self, sig: c_int, which: |&Job| -> bool) {
+        for (&pid, job) in self.state.lock().pids.iter() {
+            if which(job) {
+                signal_group(pid, sig);
+            }
+        }
+    }
+
+    /// Kill every build, now and to come: ask them to stop with
+    /// `SIGTERM`, and then make sure with `SIGKILL` once `grace_secs`
+    /// have passed.
+    pub fn kill_all(&self, grace_secs: i64) {
+        self.state.lock().killing_all = true;
+        self.signal(SIGTERM, |_| true);
+        let mut timer = Timer::new().ok().expect("No timer??");
+        timer.sleep(Duration::seconds(grace_secs));
+        self.signal(SIGKILL, |_| true);
+    }
+
+    /// Mark `jobs` as killed, and ask what they're running to stop
+    /// with `SIGTERM`. `force_kill` makes sure.
+    pub fn kill_building(&self, jobs: &[Job]) {
+        {
+            let mut state = self.state.lock();
+            for job in jobs.iter() {
+                state.killed.insert(job.clone());
+            }
+        }
+        self.signal(SIGTERM, |j| jobs.contains(j));
+    }
+
+    /// Send `SIGKILL` to whatever `jobs` are still running once
+    /// `grace_secs` have passed.
+    pub fn force_kill(&self, jobs: &[Job], grace_secs: i64) {
+        let mut timer = Timer::new().ok().expect("No timer??");
+        timer.sleep(Duration::seconds(grace_secs));
+        self.signal(SIGKILL, |j| jobs.contains(j));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use build::test_job;
+    use super::{ChildProcesses, Signal, SIGTERM};
+    use super::{Interrupt, Terminate, HangUp, User1, User2};
+
+    #[test]
+    fn killing_a_build_marks_only_it() {
+        let children = ChildProcesses::new();
+        children.kill_building(&[test_job(None)]);
+        assert!(children.is_killed(&test_job(None)));
+        assert!(!children.is_killed(&test_job(Some("debug"))));
+
+        children.forget_killed(&test_job(None));
+        assert!(!children.is_killed(&test_job(None)));
+    }
+
+    #[test]
+    fn killing_everything_includes_builds_to_come() {
+        let children = ChildProcesses::new();
+        children.kill_all(0);
+        assert!(children.is_killed(&test_job(Some("debug"))));
+
+        children.forget_killed(&test_job(Some("debug")));
+        assert!(children.is_killed(&test_job(Some("debug"))));
+    }
+
+    #[test]
+    fn sigterm_is_caught_by_hand() {
+        assert_eq!(Terminate.signum(), Err(SIGTERM));
+        assert_eq!(Signal::from_number(SIGTERM), Some(Terminate));
+        for &s in [Interrupt, HangUp, User1, User2].iter() {
+            let signum = s.signum().ok().expect("Listener knows it");
+            assert_eq!(Signal::from_signum(signum), Some(s));
+        }
+    }
+}
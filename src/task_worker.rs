@@ -1,44 +1,127 @@
-use std::io::process::ProcessOutput;
-use std::{task, str, comm};
+use std::collections::TreeMap;
+use std::io::BufferedReader;
+use std::io::process::{ExitSignal, ExitStatus};
+use std::io::timer::Timer;
+use std::time::Duration;
+use std::{task, comm};
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicInt, AtomicUint, INIT_ATOMIC_UINT, SeqCst};
 
-use Command;
-use build::{BuildInstruction, BuildResult};
+use time;
+
+use {Command, Variant};
+use build::{BuildInstruction, BuildResult, BuildSettings, BuildVars, Job, Step, Timeouts};
 use build;
+use build_log::BuildLog;
+use cgroup::Cgroup;
+use coordinator;
+use coordinator::Event;
 use git::Repo;
+use output;
+use rusage;
+use signals;
+use signals::ChildProcesses;
+
+static NEXT_ID: AtomicUint = INIT_ATOMIC_UINT;
+
+/// How many times to try to clone and check out a commit before giving
+/// up on building it.
+static SETUP_ATTEMPTS: uint = 3;
+
+/// How long to wait before trying to set up a build again, the first
+/// time; it doubles after that.
+static SETUP_RETRY_SECS: i64 = 5;
+
+/// How long a command that has timed out gets to exit after `SIGTERM`
+/// before being killed.
+static TIMEOUT_GRACE_SECS: i64 = 10;
+
+/// A fresh id for a worker, to identify the `Event`s it sends.
+pub fn next_id() -> uint {
+    NEXT_ID.fetch_add(1, SeqCst)
+}
 
 pub struct TaskWorker {
-    pub stream: (Sender<BuildInstruction>, Receiver<BuildResult>)
+    sender: Sender<BuildInstruction>,
+    /// The name of the remote worker at the other end of `sender`, or
+    /// `None` if it is a task in this process.
+    pub remote: Option<String>,
+    /// The build this worker was last sent and hasn't finished yet.
+    pub current: Option<Job>,
+    /// When `current` was sent, as a UNIX timestamp.
+    pub started: i64,
 }
 
 impl Drop for TaskWorker {
     fn drop(&mut self) {
-        error!("TaskWorker dropped!")
+        debug!("TaskWorker dropped!")
+    }
+}
+
+/// Tells the coordinator when the task holding it finishes, whether
+/// normally or by failing.
+struct Farewell {
+    id: uint,
+    events: Sender<Event>,
+}
+
+impl Drop for Farewell {
+    fn drop(&mut self) {
+        let _ = self.events.send_opt(coordinator::Lost(self.id));
     }
 }
 
 impl TaskWorker {
-    pub fn send(&self, bi: BuildInstruction) {
-        self.stream.ref0().send(bi)
+    pub fn send(&mut self, bi: BuildInstruction) {
+        let build::BuildJob(ref job) = bi;
+        self.current = Some(job.clone());
+        self.started = time::get_time().sec;
+        // if this fails, the worker is gone, and a `Lost` is on its
+        // way.
+        let _ = self.sender.send_opt(bi);
     }
 
-    pub fn recv_opt(&self) -> Result<BuildResult, ()> {
-        self.stream.ref1().recv_opt()
+    /// The name to record builds done by this worker under: the
+    /// remote worker's name, or `local`.
+    pub fn name(&self) -> &str {
+        match self.remote {
+            Some(ref name) => name.as_slice(),
+            None => "local"
+        }
+    }
+
+    /// Wrap a channel to something doing builds, which reports back
+    /// via `Event`s.
+    pub fn from_sender(sender: Sender<BuildInstruction>,
+                       remote: Option<String>) -> TaskWorker {
+        TaskWorker {
+            sender: sender,
+            remote: remote,
+            current: None,
+            started: 0,
+        }
     }
 
     /// Create a new TaskWorker, which does builds in build_dir,
-    /// cloning from `canonical_repo`.
-    pub fn new(build_dir: Path,
+    /// cloning from `canonical_repo`, as `settings` says, with their
+    /// artifacts going to `output_dir/<hash>[/<variant>]` (if
+    /// anywhere), and reports on them to `events` as worker `id`. The
+    /// processes it starts are tracked in `children`.
+    pub fn new(id: uint,
+               events: Sender<Event>,
+               build_dir: Path,
                canonical_repo: Arc<Repo>,
-               build_commands: Arc<Vec<Command>>) -> TaskWorker {
-        let (outer_tx, rx) = comm::channel();
-        let (tx, outer_rx) = comm::channel();
-        let ret = TaskWorker {
-            stream: (outer_tx, outer_rx)
-        };
+               build_commands: Arc<Vec<Command>>,
+               variants: Arc<Vec<Variant>>,
+               settings: BuildSettings,
+               output_dir: Option<Path>,
+               children: Arc<ChildProcesses>) -> TaskWorker {
+        let (tx, rx) = comm::channel();
+        let ret = TaskWorker::from_sender(tx, None);
 
         task::spawn(proc() {
+            let farewell = Farewell { id: id, events: events };
             loop {
                 let instr = match rx.recv_opt() {
                     Ok(instr) => instr,
@@ -49,25 +132,18 @@ impl TaskWorker {
                 };
 
                 let result = match instr {
-                    build::BuildHash(hash) => {
-                        println!("Building {}", hash.value)
-
-                        // foo/bar/0088119922aa33bb...77ff
-                        let hash_dir = build_dir.join(hash.value.as_slice());
-                        let subrepo = canonical_repo.new_subrepo(hash_dir);
-                        subrepo.checkout(hash.value.as_slice());
-
-                        if run_build(&subrepo, build_commands.as_slice()) {
-                            build::Success(build::Local(subrepo.path), hash)
-                        } else {
-                            build::Failure(hash)
-                        }
+                    build::BuildJob(job) => {
+                        build_commit(&build_dir, &*canonical_repo,
+                                     build_commands.as_slice(), variants.as_slice(),
+                                     &settings, output_dir.as_ref(), &*children, job)
                     }
                 };
 
                 debug!("Finished a built with {}", result);
                 // finished this build.
-                tx.send(result)
+                if farewell.events.send_opt(coordinator::Finished(id, result)).is_err() {
+                    break
+                }
             }
         });
 
@@ -75,21 +151,373 @@ impl TaskWorker {
     }
 }
 
-fn run_build(repo: &Repo, commands: &[Command]) -> bool {
+/// Check out the commit of `job` into its own directory inside
+/// `build_dir` (cloned from `canonical_repo`) and run the commands of
+/// its variant there (`build_commands`, unless the variant has its
+/// own), as `settings` says. `output_dir` is where the artifacts of
+/// builds go on this machine, if anywhere.
+pub fn build_commit(build_dir: &Path,
+                    canonical_repo: &Repo,
+                    build_commands: &[Command],
+                    variants: &[Variant],
+                    settings: &BuildSettings,
+                    output_dir: Option<&Path>,
+                    children: &ChildProcesses,
+                    job: Job) -> BuildResult {
+    println!("Building {}", job.key())
+
+    let variant = job.variant.as_ref().map(|name| Variant::find(variants, name.as_slice()));
+    let commands = match variant {
+        Some(v) => v.commands(build_commands),
+        None => build_commands
+    };
+
+    let log = Arc::new(Mutex::new(BuildLog::create(build_dir, &job, &settings.logs)));
+    log.lock().note(format!("building {}", job.key()).as_slice());
+
+    let hash = job.hash.clone();
+    // foo/bar/0088119922aa33bb...77ff[-variant]: one level, so that
+    // removing it leaves nothing behind.
+    let hash_dir = build_dir.join(job.file_name());
+    let subrepo = match set_up(canonical_repo, &hash_dir, children, &job, &mut *log.lock()) {
+        Ok(repo) => repo,
+        Err(step) => {
+            let outcome = if children.is_killed(&job) {
+                build::Interrupted
+            } else {
+                build::Infrastructure
+            };
+            log.lock().note(format!("finished: {}", outcome.status()).as_slice());
+            return BuildResult {
+                job: job,
+                outcome: outcome,
+                steps: vec![step],
+                usage: None,
+            }
+        }
+    };
+
+    let vars = BuildVars {
+        hash: hash.clone(),
+        variant: job.variant.clone(),
+        build_dir: subrepo.path.clone(),
+        output_dir: output_dir.map(|d| d.join(job.key())),
+        commit_time: subrepo.ctime(&hash),
+    };
+    let cgroup = settings.cgroups.as_ref().and_then(|c| {
+        match Cgroup::create(c, &job) {
+            Ok(cgroup) => Some(cgroup),
+            Err(e) => {
+                warn!("couldn't create a cgroup for {}: {}", job.key(), e);
+                log.lock().note(format!("couldn't create a cgroup: {}", e).as_slice());
+                None
+            }
+        }
+    });
+    let steps = run_build(&subrepo, &job, &vars, commands, variant, settings, cgroup.as_ref(),
+                          children, &log);
+    let usage = cgroup.map(|c| c.finish());
+    match usage {
+        Some(ref usage) => log.lock().note(usage.describe().as_slice()),
+        None => {}
+    }
+
+    // however far it got, a killed build doesn't count.
+    let outcome = if children.is_killed(&job) {
+        build::Interrupted
+    } else if steps.iter().all(|s| s.success()) {
+        build::Success(build::Local(subrepo.path))
+    } else if steps.iter().any(|s| s.outcome == build::StepTimedOut) {
+        build::TimedOut
+    } else if steps.iter().any(|s| match s.outcome { build::Lost(_) => true, _ => false }) {
+        // not the commit's fault.
+        build::Infrastructure
+    } else {
+        build::Failure
+    };
+    // so that anything following the log knows it's done.
+    log.lock().note(format!("finished: {}", outcome.status()).as_slice());
+    BuildResult { job: job, outcome: outcome, steps: steps, usage: usage }
+}
+
+/// Clone `canonical_repo` into `dir` and check out the commit of `job`
+/// there, trying again (from scratch) with backoff if that fails,
+/// unless `job` is killed. On failure, the `Err` describes what went
+/// wrong.
+fn set_up(canonical_repo: &Repo, dir: &Path, children: &ChildProcesses, job: &Job,
+          log: &mut BuildLog) -> Result<Repo, Step> {
+    let hash = &job.hash;
+    let start_ms = now_ms();
+    let mut delay = SETUP_RETRY_SECS;
+    let mut attempt = 1;
+    loop {
+        if children.is_killed(job) {
+            log.note("killed while setting up");
+            return Err(Step {
+                command: format!("git clone {} && git checkout {}",
+                                 canonical_repo.path.display(), hash.value),
+                outcome: build::NotRun,
+                duration_ms: now_ms() - start_ms,
+                log: Some(format!("{}", log.path().display())),
+                env: None,
+                resources: None,
+            })
+        }
+        log.note(format!("$ git clone {} {}", canonical_repo.path.display(),
+                         dir.display()).as_slice());
+        let result = match canonical_repo.new_subrepo(dir.clone(), children, job,
+                                                      |s, l| log.output(s, l)) {
+            Ok(repo) => {
+                log.note(format!("$ git checkout {}", hash.value).as_slice());
+                repo.checkout(hash.value.as_slice(), children, job,
+                              |s, l| log.output(s, l)).map(|()| repo)
+            }
+            Err(e) => Err(e)
+        };
+        let error = match result {
+            Ok(repo) => return Ok(repo),
+            Err(e) => e
+        };
+
+        warn!("setting up the build of {} failed (attempt {} of {}): {}",
+              hash.value, attempt, SETUP_ATTEMPTS, error);
+        log.note(format!("setting up failed (attempt {} of {}): {}",
+                         attempt, SETUP_ATTEMPTS, error).as_slice());
+        // whatever was left there can't be trusted.
+        output::remove_build_dir(dir);
+        if attempt == SETUP_ATTEMPTS {
+            return Err(Step {
+                command: format!("git clone {} && git checkout {}",
+                                 canonical_repo.path.display(), hash.value),
+                outcome: build::Error(error),
+                duration_ms: now_ms() - start_ms,
+                log: Some(format!("{}", log.path().display())),
+                env: None,
+                resources: None,
+            })
+        }
+
+        // a second at a time, so that being killed isn't held up.
+        let mut timer = Timer::new().ok().expect("No timer??");
+        for _ in range(0, delay) {
+            if children.is_killed(job) {
+                break
+            }
+            timer.sleep(Duration::seconds(1));
+        }
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// Milliseconds since some arbitrary point.
+fn now_ms() -> i64 {
+    (time::precise_time_ns() / 1_000_000) as i64
+}
+
+/// Copy everything from `r` to `log` a line at a time, as `stream`,
+/// in another task, storing the time of the last line in
+/// `last_output`. The returned port receives `()` once `r` is closed.
+fn log_output<R: Reader + Send>(r: R, stream: &'static str, log: Arc<Mutex<BuildLog>>,
+                                last_output: Arc<AtomicInt>) -> Receiver<()> {
+    let (tx, rx) = comm::channel();
+    task::spawn(proc() {
+        let mut r = BufferedReader::new(r);
+        loop {
+            match r.read_until(b'\n') {
+                Ok(line) => {
+                    last_output.store(time::get_time().sec as int, SeqCst);
+                    log.lock().output(stream, line.as_slice());
+                }
+                Err(_) => break
+            }
+        }
+        // nobody may be waiting any more, if it stayed open too long.
+        let _ = tx.send_opt(());
+    });
+    rx
+}
+
+/// The earliest of the deadlines that apply, and why it applies.
+fn deadline(timeouts: &Timeouts, command: &Command,
+            build_start: i64, command_start: i64, last_output: i64) -> Option<(i64, &'static str)> {
+    let limits = [
+        (command.timeout_seconds.or(timeouts.command).map(|t| command_start + t),
+         "ran for too long"),
+        (timeouts.build.map(|t| build_start + t), "the build ran for too long"),
+        (timeouts.no_output.map(|t| last_output + t), "no output for too long"),
+    ];
+    limits.iter()
+        .filter_map(|&(at, why)| at.map(|at| (at, why)))
+        .min_by(|&(at, _)| at)
+}
+
+/// Run `commands` in order, until one doesn't succeed or `job` is
+/// killed, returning how each went.
+fn run_build(repo: &Repo, job: &Job, vars: &BuildVars, commands: &[Command], variant: Option<&Variant>,
+             settings: &BuildSettings, cgroup: Option<&Cgroup>, children: &ChildProcesses,
+             log: &Arc<Mutex<BuildLog>>) -> Vec<Step> {
+    let build_start = time::get_time().sec;
+    let mut base_env = settings.environment.base(|s| vars.expand(s));
+    match variant.and_then(|v| v.env.as_ref()) {
+        Some(env) => {
+            for (name, value) in env.iter() {
+                base_env.insert(name.clone(), vars.expand(value.as_slice()));
+            }
+        }
+        None => {}
+    }
+    for (name, value) in vars.env().into_iter() {
+        base_env.insert(name, value);
+    }
+    // outermost first: the sandbox and anything else happen inside
+    // the cgroup.
+    let mut prefix = cgroup.map(|c| c.prefix()).unwrap_or(Vec::new());
+    match settings.sandbox {
+        Some(ref sandbox) => prefix.push_all(sandbox.prefix(&vars.build_dir).as_slice()),
+        None => {}
+    }
+    prefix.push_all(settings.limits.prefix().as_slice());
+    let mut steps = Vec::new();
     for command in commands.iter() {
-        let ProcessOutput { status, output, error } =
-            repo.exec(command.name.as_slice(), command.args.as_slice());
-        debug!("status success: {}", status.success());
-        if !status.success() {
-            warn!("run_build {} {} failed with {}: {} {}",
-                   command.name,
-                   command.args,
-                   status,
-                   str::from_utf8(output.as_slice()),
-                   str::from_utf8(error.as_slice()));
-
-            return false;
+        if children.is_killed(job) || steps.iter().any(|s: &Step| !s.success()) {
+            steps.push(Step {
+                command: command.line(),
+                outcome: build::NotRun,
+                duration_ms: 0,
+                log: None,
+                env: None,
+                resources: None,
+            });
+            continue
         }
+        log.lock().note(format!("$ {}", command.line()).as_slice());
+        let env = command.env(&base_env, |s| vars.expand(s));
+        let mut step = run_command(repo, job, vars, command, &env, prefix.as_slice(),
+                                   &settings.timeouts, children, build_start, log);
+        if settings.environment.is_hermetic() {
+            step.env = Some(env);
+        }
+        log.lock().note(step.describe().as_slice());
+        steps.push(step);
+    }
+    steps
+}
+
+fn run_command(repo: &Repo, job: &Job, vars: &BuildVars, command: &Command,
+               env: &TreeMap<String, String>, prefix: &[String], timeouts: &Timeouts,
+               children: &ChildProcesses, build_start: i64,
+               log: &Arc<Mutex<BuildLog>>) -> Step {
+    let hash = &vars.hash;
+    let log_path = Some(format!("{}", log.lock().path().display()));
+    let start_ms = now_ms();
+    // each command gets its own process group, so that it can be
+    // left to finish or killed as a whole when we're interrupted.
+    let spawned = command.process(&repo.path, env, prefix, |s| vars.expand(s),
+                                  |s| vars.expand_for_shell(s))
+        .detached()
+        .spawn();
+    let mut process = match spawned {
+        Ok(p) => p,
+        Err(e) => {
+            warn!("couldn't run {}: {}", command.line(), e);
+            return Step {
+                command: command.line(),
+                outcome: build::Error(format!("{}", e)),
+                duration_ms: 0,
+                log: log_path,
+                env: None,
+                resources: None,
+            }
+        }
+    };
+    let pid = process.id();
+    children.add(pid, job);
+
+    let command_start = time::get_time().sec;
+    let last_output = Arc::new(AtomicInt::new(command_start as int));
+    drop(process.stdin.take());
+    let output = log_output(process.stdout.take().unwrap(), "out", log.clone(),
+                            last_output.clone());
+    let error = log_output(process.stderr.take().unwrap(), "err", log.clone(),
+                           last_output.clone());
+
+    // check the clock every tenth of a second while waiting.
+    let mut timer = Timer::new().ok().expect("No timer??");
+    let mut killed_at = None;
+    let mut waited = None;
+    loop {
+        match rusage::try_wait(pid) {
+            Ok(Some(w)) => { waited = Some(w); break }
+            Ok(None) => {}
+            Err(e) => {
+                warn!("waiting for {} building {} failed: {}", command.line(), hash.value, e);
+                // nothing can tell when it's finished now, so it
+                // mustn't carry on.
+                signals::signal_group(pid, signals::SIGKILL);
+                children.remove(pid);
+                process.forget();
+                return Step {
+                    command: command.line(),
+                    outcome: build::Lost(format!("waiting for it failed: {}", e)),
+                    duration_ms: now_ms() - start_ms,
+                    log: log_path,
+                    env: None,
+                    resources: None,
+                }
+            }
+        }
+        timer.sleep(Duration::milliseconds(100));
+
+        let now = time::get_time().sec;
+        match killed_at {
+            None => {
+                match deadline(timeouts, command, build_start, command_start,
+                               last_output.load(SeqCst) as i64) {
+                    Some((at, why)) if now >= at => {
+                        warn!("killing {} building {}: {}", command.line(), hash.value, why);
+                        signals::signal_group(pid, signals::SIGTERM);
+                        killed_at = Some(now);
+                    }
+                    _ => {}
+                }
+            }
+            Some(t) if now - t >= TIMEOUT_GRACE_SECS => {
+                signals::signal_group(pid, signals::SIGKILL);
+            }
+            Some(_) => {}
+        }
+    }
+    let (status, resources) = waited.unwrap();
+    // it's been reaped, so dropping it would wait for it again.
+    process.forget();
+    children.remove(pid);
+    if !signals::await_output(pid, vec![output, error]) {
+        warn!("{} building {} left its output open, killed what was left",
+              command.line(), hash.value);
+        log.lock().note("the command left something running with its output open; \
+                         killed it");
+    }
+    let duration_ms = now_ms() - start_ms;
+
+    debug!("status success: {}", status.success());
+    if !status.success() && killed_at.is_none() {
+        warn!("run_build {} failed with {}, see {}",
+               command.line(),
+               status,
+               log.lock().path().display());
+    }
+
+    Step {
+        command: command.line(),
+        outcome: match status {
+            _ if killed_at.is_some() => build::StepTimedOut,
+            ExitStatus(code) => build::Exited(code),
+            ExitSignal(sig) => build::Signalled(sig),
+        },
+        duration_ms: duration_ms,
+        log: log_path,
+        env: None,
+        resources: Some(resources),
     }
-    true
 }